@@ -2,5 +2,9 @@ extern crate loom;
 use std::env::args;
 
 pub fn main() {
-    loom::daemon::run(args().collect()).and_then(|mut x| Some(x.join().unwrap()));
+    let sigs = loom::daemon::block_signals().expect("block signals");
+    loom::daemon::run(args().collect()).and_then(|mut x| {
+        x.on_signals(sigs);
+        Some(x.join().unwrap())
+    });
 }
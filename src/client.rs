@@ -3,15 +3,36 @@ use getopts::Options;
 use std::string::String;
 use data_encoding::BASE32HEX;
 use wallet::{EncryptedWallet, Wallet, to32b};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fs::File;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json;
+use std::time::{Duration, Instant};
+use std::net::{SocketAddr, ToSocketAddrs};
 use net;
-use result::Result;
 use data;
+use history::PAGE;
+use data::Message;
+use result::{from_option, Error, Result};
 
 struct Cfg {
     host: String,
     wallet: String,
+    retry: net::Retry,
+    fee: u64,
+    network: Option<u32>,
+    /// identity key of the node, `LastHash` replies must be signed by it,
+    /// `-K` or else the one it answers `GetInfo` with
+    node: Cell<Option<[u8; 32]>>,
+    /// the last `LastHash` and when it was fetched
+    last_hash: Cell<Option<(Instant, Message)>>,
 }
 
+/// how long a fetched PoH entry is used for signing before asking again
+const LAST_HASH_TTL: Duration = Duration::from_secs(1);
+
 fn getpass<T>(r: Option<T>) -> String
 where
     T: ::std::io::BufRead,
@@ -29,7 +50,7 @@ fn vec_to_array(v: Vec<u8>) -> [u8; 32] {
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} FILE [options]", program);
+    let brief = format!("Usage: {} [info] [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
@@ -56,7 +77,224 @@ where
         .expect("write");
 }
 
-fn transfer<T>(cfg: &Cfg, r: Option<T>, from: String, to: String, amnt: u64) -> Result<()>
+fn transfer<T>(
+    cfg: &Cfg,
+    r: Option<T>,
+    from: String,
+    to: String,
+    amnt: u64,
+    compact: bool,
+) -> Result<()>
+where
+    T: ::std::io::BufRead,
+{
+    let pass = getpass(r);
+    let w = load_wallet(cfg, pass);
+    let fpk = vec_to_array(BASE32HEX.decode(from.as_bytes()).expect("from key"));
+    let tpk = vec_to_array(BASE32HEX.decode(to.as_bytes()).expect("to key"));
+    let kix = w.find(fpk)?;
+    let net = network(cfg)?;
+    if compact {
+        return compact_transfer(cfg, &w, kix, (fpk, tpk), amnt, net);
+    }
+    let msg = stamp(cfg, &w, kix, w.tx(kix, tpk, amnt, cfg.fee, net))?;
+    match request(cfg, &msg, &once(cfg)) {
+        Err(Error::Timeout) | Ok(_) => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+/// transfers are only answered when they are rejected, so wait for that
+/// once instead of resending
+fn once(cfg: &Cfg) -> net::Retry {
+    net::Retry {
+        retries: 0,
+        ..cfg.retry
+    }
+}
+
+/// where the account indexes used by `-C` are kept between runs
+fn index_file(cfg: &Cfg) -> String {
+    format!("{}.index", cfg.wallet)
+}
+
+/// where the identity keys of the nodes are pinned on first use
+fn pin_file(cfg: &Cfg) -> String {
+    format!("{}.nodes", cfg.wallet)
+}
+
+/// a map kept in a JSON file next to the wallet, a missing or unreadable
+/// file is an empty map
+fn load_map<V: DeserializeOwned>(path: &str) -> HashMap<String, V> {
+    File::open(path)
+        .ok()
+        .and_then(|f| serde_json::from_reader(f).ok())
+        .unwrap_or_default()
+}
+
+fn save_map<V: Serialize>(path: &str, map: &HashMap<String, V>) -> Result<()> {
+    let f = File::create(path)?;
+    serde_json::to_writer(f, map)?;
+    Ok(())
+}
+
+fn load_indexes(cfg: &Cfg) -> HashMap<String, u64> {
+    load_map(&index_file(cfg))
+}
+
+fn save_indexes(cfg: &Cfg, ixs: &HashMap<String, u64>) -> Result<()> {
+    save_map(&index_file(cfg), ixs)
+}
+
+/// the cached index of `key`, asking the node only for keys not seen yet
+fn cached_index(
+    cfg: &Cfg,
+    ixs: &mut HashMap<String, u64>,
+    w: &Wallet,
+    kix: usize,
+    key: [u8; 32],
+    net: u32,
+) -> Result<u64> {
+    let k = BASE32HEX.encode(&key);
+    if let Some(ix) = ixs.get(&k) {
+        return Ok(*ix);
+    }
+    let ix = get_index(cfg, w, kix, key, net)?;
+    ixs.insert(k, ix);
+    Ok(ix)
+}
+
+/// send a transfer by account index, the indexes move when the node resizes
+/// its table, so a stale one is dropped from the cache and fetched again once
+fn compact_transfer(
+    cfg: &Cfg,
+    w: &Wallet,
+    kix: usize,
+    (fpk, tpk): ([u8; 32], [u8; 32]),
+    amnt: u64,
+    net: u32,
+) -> Result<()> {
+    let s = net::socket()?;
+    let addr = resolve(&cfg.host)?;
+    let mut ixs = load_indexes(cfg);
+    let mut rv = Err(Error::StaleIndex);
+    for _ in 0..2 {
+        let pair = (
+            cached_index(cfg, &mut ixs, w, kix, fpk, net)?,
+            cached_index(cfg, &mut ixs, w, kix, tpk, net)?,
+        );
+        let mut tx = w.compact_tx(kix, pair, tpk, amnt, cfg.fee, net);
+        w.set_compact_lvh(kix, &mut tx, last_hash(cfg)?.pld.lvh_count);
+        rv = match net::request_compact(&s, &tx, addr, &once(cfg)) {
+            Err(Error::Timeout) => Ok(()),
+            Err(e) => Err(e),
+            Ok(r) => match r[0].pld.state {
+                data::State::FeeTooLow => Err(Error::FeeTooLow),
+                data::State::WrongNetwork => Err(Error::WrongNetwork),
+                data::State::WrongVersion => Err(Error::WrongVersion),
+                data::State::StaleIndex => Err(Error::StaleIndex),
+                _ => Ok(()),
+            },
+        };
+        match rv {
+            Err(Error::StaleIndex) => {
+                ixs.remove(&BASE32HEX.encode(&fpk));
+                ixs.remove(&BASE32HEX.encode(&tpk));
+            }
+            _ => break,
+        }
+    }
+    save_indexes(cfg, &ixs)?;
+    rv
+}
+
+/// send `msg` to the node and return the reply and what follows it in its
+/// packet, a message the node rejected for its fee or network is an error
+fn request(cfg: &Cfg, msg: &Message, retry: &net::Retry) -> Result<Vec<Message>> {
+    let s = net::socket()?;
+    let addr = resolve(&cfg.host)?;
+    let rv = net::request_all(&s, msg, addr, retry)?;
+    match rv[0].pld.state {
+        data::State::FeeTooLow => Err(Error::FeeTooLow),
+        data::State::WrongNetwork => Err(Error::WrongNetwork),
+        data::State::WrongVersion => Err(Error::WrongVersion),
+        _ => Ok(rv),
+    }
+}
+
+/// the network to sign for, `-N` or else the one of the node's genesis
+fn network(cfg: &Cfg) -> Result<u32> {
+    if let Some(n) = cfg.network {
+        return Ok(n);
+    }
+    let mut msg = Message::default();
+    msg.pld.kind = data::Kind::GetGenesis;
+    let rmsg = request(cfg, &msg, &cfg.retry)?[0];
+    Ok(rmsg.pld.get_gen().network_id)
+}
+
+/// the identity key of the node, `-K` or else the one pinned for the host,
+/// `GetInfo` isn't signed so its key is only trusted the first time a host
+/// is seen, a node that later signs with another key is `BadSignature`
+fn node(cfg: &Cfg) -> Result<[u8; 32]> {
+    if let Some(k) = cfg.node.get() {
+        return Ok(k);
+    }
+    let mut pins: HashMap<String, String> = load_map(&pin_file(cfg));
+    let k = match pins.get(&cfg.host) {
+        Some(k) => {
+            let k = BASE32HEX.decode(k.as_bytes()).or_else(|_| Err(Error::Corrupt))?;
+            if k.len() != 32 {
+                return Err(Error::Corrupt);
+            }
+            vec_to_array(k)
+        }
+        None => {
+            let mut msg = Message::default();
+            msg.pld.kind = data::Kind::GetInfo;
+            let k = request(cfg, &msg, &cfg.retry)?[0].pld.from;
+            pins.insert(cfg.host.clone(), BASE32HEX.encode(&k));
+            save_map(&pin_file(cfg), &pins)?;
+            k
+        }
+    };
+    cfg.node.set(Some(k));
+    Ok(k)
+}
+
+/// the node's latest PoH entry as a `LastHash`, fetched again once it is
+/// `LAST_HASH_TTL` old, it must be signed by the node for this request's
+/// nonce so an old entry can't be replayed
+fn last_hash(cfg: &Cfg) -> Result<Message> {
+    if let Some((at, e)) = cfg.last_hash.get() {
+        if at.elapsed() < LAST_HASH_TTL {
+            return Ok(e);
+        }
+    }
+    let node = node(cfg)?;
+    let req = Wallet::get_last_hash();
+    let rv = request(cfg, &req, &cfg.retry)?;
+    let e = *rv.get(1).ok_or(Error::Corrupt)?;
+    if e.pld.kind != data::Kind::LastHash {
+        return Err(Error::Corrupt);
+    }
+    if e.pld.from != node || e.pld.get_nonce().nonce[..] != req.sig[..32] || !Wallet::verify(&e) {
+        return Err(Error::BadSignature);
+    }
+    cfg.last_hash.set(Some((Instant::now(), e)));
+    Ok(e)
+}
+
+/// sign `msg` again pointing at the node's latest PoH entry
+fn stamp(cfg: &Cfg, w: &Wallet, kix: usize, mut msg: Message) -> Result<Message> {
+    let e = last_hash(cfg)?;
+    w.set_lvh(kix, &mut msg, e.pld.lvh, e.pld.lvh_count);
+    Ok(msg)
+}
+
+/// ask the node what a transfer would do without making it, returns the
+/// `Kind::Simulated` reply
+fn dry_run<T>(cfg: &Cfg, r: Option<T>, from: String, to: String, amnt: u64) -> Result<Message>
 where
     T: ::std::io::BufRead,
 {
@@ -65,17 +303,90 @@ where
     let fpk = BASE32HEX.decode(from.as_bytes()).expect("from key");
     let tpk = BASE32HEX.decode(to.as_bytes()).expect("to key");
     let kix = w.find(vec_to_array(fpk))?;
-    let msg = w.tx(kix, vec_to_array(tpk), amnt, 1);
+    let msg = w.simulate(kix, vec_to_array(tpk), amnt, cfg.fee, network(cfg)?);
+    let msg = stamp(cfg, &w, kix, msg)?;
+    let rmsg = request(cfg, &msg, &cfg.retry)?[0];
+    let r = rmsg.pld.get_result();
+    let outcome = if rmsg.pld.state == data::State::Deposited {
+        "would succeed"
+    } else {
+        "would fail"
+    };
+    println!(
+        "transfer {}, source balance {:?} destination balance {:?}",
+        outcome, r.from, r.to
+    );
+    Ok(rmsg)
+}
+
+fn balance<T>(cfg: &Cfg, r: Option<T>, from: String, addr: String) -> Result<()>
+where
+    T: ::std::io::BufRead,
+{
+    let pass = getpass(r);
+    let w = load_wallet(cfg, pass);
+    let fpk = BASE32HEX.decode(from.as_bytes()).expect("from key");
+    let tpk = BASE32HEX.decode(addr.as_bytes()).expect("target key");
+    let kix = w.find(vec_to_array(fpk))?;
+    let msg = w.check_balance(kix, vec_to_array(tpk), cfg.fee, network(cfg)?);
+    let msg = stamp(cfg, &w, kix, msg)?;
+    let rmsg = request(cfg, &msg, &cfg.retry)?[0];
+    println!("balance is {:?}", rmsg.pld.get_bal().amount);
+    Ok(())
+}
+
+/// free balance query of every address in `addrs`, no wallet needed,
+/// returns the balances with `None` for addresses without an account
+fn query(cfg: &Cfg, addrs: Vec<String>) -> Result<Vec<Option<u64>>> {
+    let msgs: Vec<Message> = addrs
+        .iter()
+        .map(|a| {
+            let k = BASE32HEX.decode(a.as_bytes()).expect("target key");
+            Wallet::query(vec_to_array(k))
+        })
+        .collect();
     let s = net::socket()?;
-    s.connect(cfg.host.clone())?;
-    let mut num = 0;
-    while num < 1 {
-        net::write(&s, &[msg], &mut num)?;
+    let rv = net::request_many(&s, &msgs, resolve(&cfg.host)?, &cfg.retry)?;
+    let mut bals = Vec::new();
+    for (a, m) in addrs.iter().zip(rv.iter()) {
+        if m.pld.state == data::State::Withdrawn {
+            println!("{} balance is {:?}", a, m.pld.get_query().amount);
+            bals.push(Some(m.pld.get_query().amount));
+        } else {
+            println!("{} has no account", a);
+            bals.push(None);
+        }
     }
+    Ok(bals)
+}
+
+/// print what the node says about itself
+fn info(cfg: &Cfg) -> Result<()> {
+    let mut msg = Message::default();
+    msg.pld.kind = data::Kind::GetInfo;
+    let s = net::socket()?;
+    let rmsg = net::request(&s, &msg, resolve(&cfg.host)?, &cfg.retry)?;
+    let i = rmsg.pld.get_info();
+    println!("identity {}", BASE32HEX.encode(&rmsg.pld.from));
+    println!("version {:?}", i.version);
+    println!("supply {:?}", i.supply);
+    println!("accounts {:?} of {:?}", i.used, i.capacity);
+    println!(
+        "poh count {:?} hash {}",
+        rmsg.pld.lvh_count,
+        BASE32HEX.encode(&rmsg.pld.lvh)
+    );
     Ok(())
 }
 
-fn balance<T>(cfg: &Cfg, r: Option<T>, from: String, addr: String) -> Result<()>
+/// table index of `key`, paid for by wallet key `kix`
+fn get_index(cfg: &Cfg, w: &Wallet, kix: usize, key: [u8; 32], net: u32) -> Result<u64> {
+    let msg = stamp(cfg, w, kix, w.get_index(kix, key, cfg.fee, net))?;
+    let rmsg = request(cfg, &msg, &cfg.retry)?[0];
+    Ok(rmsg.pld.get_idx().index)
+}
+
+fn index<T>(cfg: &Cfg, r: Option<T>, from: String, addr: String) -> Result<()>
 where
     T: ::std::io::BufRead,
 {
@@ -84,22 +395,57 @@ where
     let fpk = BASE32HEX.decode(from.as_bytes()).expect("from key");
     let tpk = BASE32HEX.decode(addr.as_bytes()).expect("target key");
     let kix = w.find(vec_to_array(fpk))?;
-    let msg = w.check_balance(kix, vec_to_array(tpk), 1);
-    let s = net::socket()?;
-    s.connect(cfg.host.clone())?;
-    let mut num = 0;
-    while num < 1 {
-        net::write(&s, &[msg], &mut num)?;
-    }
-    let mut rmsgs = data::Messages::new();
-    rmsgs
-        .with_mut(|m, d| net::read_from(&s, m, d))
-        .expect("read rmsgs");
-    assert_eq!(rmsgs.data[0].0, 1);
-    println!("balance is {:?}", rmsgs.msgs[0].pld.get_bal().amount);
+    let ix = get_index(cfg, &w, kix, vec_to_array(tpk), network(cfg)?)?;
+    println!("index is {:?}", ix);
     Ok(())
 }
 
+/// print the history of `from` a page at a time, newest first, the node only
+/// answers for the paying account
+fn history<T>(cfg: &Cfg, r: Option<T>, from: String) -> Result<()>
+where
+    T: ::std::io::BufRead,
+{
+    let pass = getpass(r);
+    let w = load_wallet(cfg, pass);
+    let fpk = vec_to_array(BASE32HEX.decode(from.as_bytes()).expect("from key"));
+    let kix = w.find(fpk)?;
+    let net = network(cfg)?;
+    let mut start = 0;
+    loop {
+        let msg = w.get_history(kix, fpk, start, PAGE as u32, cfg.fee, net);
+        let msg = stamp(cfg, &w, kix, msg)?;
+        let rmsgs = request(cfg, &msg, &cfg.retry)?;
+        let num = rmsgs[0].pld.get_hist().num as usize;
+        for e in rmsgs[1..].iter().take(num) {
+            let h = e.pld.get_entry();
+            let dir = if e.pld.state == data::State::Withdrawn {
+                "to"
+            } else {
+                "from"
+            };
+            println!(
+                "{} {} {} fee {} poh {} sig {}",
+                dir,
+                BASE32HEX.encode(&h.counterparty),
+                h.amount,
+                e.pld.fee,
+                e.pld.lvh_count,
+                BASE32HEX.encode(&e.sig)
+            );
+        }
+        if num < PAGE {
+            return Ok(());
+        }
+        start += num as u32;
+    }
+}
+
+fn resolve(host: &str) -> Result<SocketAddr> {
+    let mut addrs = host.to_socket_addrs()?;
+    from_option(addrs.next())
+}
+
 fn list<T>(cfg: &Cfg, r: Option<T>)
 where
     T: ::std::io::BufRead,
@@ -126,12 +472,38 @@ where
     let mut cfg = Cfg {
         host: "loom.loomprotocol.com:12345".to_string(),
         wallet: "loom.wallet".to_string(),
+        retry: net::Retry::default(),
+        fee: 1,
+        network: None,
+        node: Cell::new(None),
+        last_hash: Cell::new(None),
     };
     let mut opts = Options::new();
     opts.optflag("c", "", "create a new address");
     opts.optflag("x", "", "transfer");
     opts.optflag("b", "", "check the balance of destination address");
+    opts.optflag(
+        "q",
+        "",
+        "check the balances of the destination addresses for free, no wallet needed",
+    );
+    opts.optflag("i", "", "look up the table index of destination address");
+    opts.optflag(
+        "y",
+        "history",
+        "list the recent transfers of the source address",
+    );
+    opts.optflag(
+        "C",
+        "",
+        "transfer by table index, both addresses must already exist",
+    );
     opts.optflag("l", "list", "list your addresses and balances");
+    opts.optflag(
+        "",
+        "dry-run",
+        "only check what the transfer would do, nothing is charged",
+    );
     opts.optflag("h", "help", "print this help menu");
     opts.optopt(
         "H",
@@ -140,9 +512,34 @@ where
         "HOST:PORT",
     );
     opts.optopt("W", "", "loom wallet instead of loom.wallet", "PATH");
-    opts.optopt("t", "", "destination address", "ADDRESS");
+    opts.optmulti(
+        "t",
+        "",
+        "destination address, -q takes several",
+        "ADDRESS",
+    );
     opts.optopt("f", "", "source address", "ADDRESS");
     opts.optopt("a", "", "amount", "AMOUNT");
+    opts.optopt(
+        "T",
+        "",
+        "milliseconds to wait for the first reply, doubled on every retry",
+        "MS",
+    );
+    opts.optopt("R", "", "number of times to resend a request", "NUM");
+    opts.optopt("F", "", "fee to pay, default 1", "FEE");
+    opts.optopt(
+        "K",
+        "",
+        "identity key of the node, the PoH entries it hands out must be signed by it, default the one it reported first, pinned next to the wallet",
+        "ADDRESS",
+    );
+    opts.optopt(
+        "N",
+        "",
+        "network id to sign for, default the one of the node's genesis",
+        "ID",
+    );
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
         Err(f) => panic!(f.to_string()),
@@ -157,6 +554,29 @@ where
     if matches.opt_present("W") {
         cfg.wallet = matches.opt_str("W").expect("loom wallet path");
     }
+    if let Some(t) = matches.opt_str("T") {
+        let ms = t.parse().expect("timeout is not a number");
+        cfg.retry.timeout = Duration::from_millis(ms);
+    }
+    if let Some(r) = matches.opt_str("R") {
+        cfg.retry.retries = r.parse().expect("retries is not a number");
+    }
+    if let Some(f) = matches.opt_str("F") {
+        cfg.fee = f.parse().expect("fee is not a number");
+    }
+    if let Some(k) = matches.opt_str("K") {
+        let k = BASE32HEX.decode(k.as_bytes()).expect("node key");
+        cfg.node.set(Some(vec_to_array(k)));
+    }
+    if let Some(n) = matches.opt_str("N") {
+        cfg.network = Some(n.parse().expect("network id is not a number"));
+    }
+    if matches.free.first().map(|c| c == "info").unwrap_or(false) {
+        if let Err(e) = info(&cfg) {
+            println!("info failed: {:?}", e);
+        }
+        return;
+    }
     if matches.opt_present("c") {
         new_key_pair(&cfg, reader);
         return;
@@ -165,12 +585,43 @@ where
         let from = matches.opt_str("f").expect("missing source address");
         let astr = matches.opt_str("a").expect("missing ammount");
         let a = astr.parse().expect("ammount is not a number");
-        transfer(&cfg, reader, from, to, a).expect("transfer");
+        if matches.opt_present("dry-run") {
+            if let Err(e) = dry_run(&cfg, reader, from, to, a) {
+                println!("dry run failed: {:?}", e);
+            }
+            return;
+        }
+        let compact = matches.opt_present("C");
+        if let Err(e) = transfer(&cfg, reader, from, to, a, compact) {
+            println!("transfer failed: {:?}", e);
+        }
         return;
     } else if matches.opt_present("b") {
         let from = matches.opt_str("f").expect("missing source key address");
         let to = matches.opt_str("t").expect("missing target address");
-        balance(&cfg, reader, from, to).expect("transfer");
+        if let Err(e) = balance(&cfg, reader, from, to) {
+            println!("balance failed: {:?}", e);
+        }
+        return;
+    } else if matches.opt_present("q") {
+        let to = matches.opt_strs("t");
+        assert!(!to.is_empty(), "missing target address");
+        if let Err(e) = query(&cfg, to) {
+            println!("query failed: {:?}", e);
+        }
+        return;
+    } else if matches.opt_present("i") {
+        let from = matches.opt_str("f").expect("missing source key address");
+        let to = matches.opt_str("t").expect("missing target address");
+        if let Err(e) = index(&cfg, reader, from, to) {
+            println!("index failed: {:?}", e);
+        }
+        return;
+    } else if matches.opt_present("y") {
+        let from = matches.opt_str("f").expect("missing source key address");
+        if let Err(e) = history(&cfg, reader, from) {
+            println!("history failed: {:?}", e);
+        }
         return;
     } else if matches.opt_present("l") {
         list(&cfg, reader);
@@ -181,9 +632,20 @@ where
 mod tests {
     use client;
     use daemon;
+    use data;
     use std::io::Cursor;
     use data_encoding::BASE32HEX;
-    use std::fs::remove_file;
+    use std::fs::{copy, remove_file, File};
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::env::temp_dir;
+    use std::io::Read;
+    use net;
+    use result::Error;
+    use serde_json;
+    use wallet::{to32b, Wallet};
+    use std::thread::{sleep, spawn};
+    use std::time::Duration;
 
     #[test]
     fn help_test() {
@@ -193,6 +655,32 @@ mod tests {
     fn pass() -> Option<Cursor<&'static [u8]>> {
         Some(Cursor::new(&b"foobar\n"[..]))
     }
+    /// a copy of the test wallet of its own, without the pinned keys and
+    /// cached indexes of an earlier run
+    fn wallet(name: &str) -> String {
+        let path = temp_dir().join(name).to_str().unwrap().to_string();
+        remove_wallet(&path);
+        copy("testdata/loom.wallet", &path).expect("copy wallet");
+        path
+    }
+    fn remove_wallet(path: &str) {
+        let _ = remove_file(format!("{}.nodes", path));
+        let _ = remove_file(format!("{}.index", path));
+        let _ = remove_file(path);
+    }
+    /// a wallet per port so the tests don't share pinned keys
+    fn cfg(host: &str) -> client::Cfg {
+        let port = host.rsplit(':').next().unwrap();
+        client::Cfg {
+            host: host.into(),
+            wallet: wallet(&format!("loom-client-{}", port)),
+            retry: net::Retry::default(),
+            fee: 1,
+            network: None,
+            node: Cell::new(None),
+            last_hash: Cell::new(None),
+        }
+    }
 
     #[test]
     fn add_test() {
@@ -227,12 +715,13 @@ mod tests {
             "testdata/test_accounts.json".into(),
         ];
         let mut t = daemon::run(args).expect("daemon load");
+        let w = wallet("loom-client-14346");
 
         let addr: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
         let args = vec![
             "loom".into(),
             "-W".into(),
-            "testdata/loom.wallet".into(),
+            w.clone(),
             "-H".into(),
             "127.0.0.1:14346".into(),
             "-b".into(),
@@ -243,6 +732,266 @@ mod tests {
         ];
         client::run(args, pass());
         t.shutdown().expect("success");
+        remove_wallet(&w);
+    }
+
+    #[test]
+    fn query_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14344".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-f".into(),
+            "5".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let addr: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
+        let other = BASE32HEX.encode(&[1u8; 32]);
+        let args = vec![
+            "loom".into(),
+            "-H".into(),
+            "127.0.0.1:14344".into(),
+            "-q".into(),
+            "-t".into(),
+            addr.clone(),
+            "-t".into(),
+            other.clone(),
+        ];
+        client::run(args, None::<Cursor<&'static [u8]>>);
+        let cfg = cfg("127.0.0.1:14344");
+        let bals = client::query(&cfg, vec![addr, other]).expect("query");
+        assert_eq!(bals, vec![Some(1000000000), None]);
+        t.shutdown().expect("success");
+        remove_wallet(&cfg.wallet);
+    }
+
+    #[test]
+    fn info_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14343".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let args = vec![
+            "loom".into(),
+            "info".into(),
+            "-H".into(),
+            "127.0.0.1:14343".into(),
+        ];
+        client::run(args, None::<Cursor<&'static [u8]>>);
+        t.shutdown().expect("success");
+    }
+
+    /// answer the first packet sent to `port` with `reply` of its first
+    /// message
+    fn fake_node<F>(port: u16, reply: F)
+    where
+        F: Fn(data::Message) -> Vec<data::Message> + Send + 'static,
+    {
+        let fake = net::bindall(port).expect("fake node");
+        spawn(move || {
+            let mut m = [data::Message::default(); 64];
+            let mut d = [(0, "0.0.0.0:0".parse().unwrap()); 64];
+            net::read_from(&fake, &mut m, &mut d).expect("read");
+            let mut num = 0;
+            net::send_to(&fake, &reply(m[0]), &mut num, d[0].1).expect("send");
+        });
+    }
+
+    #[test]
+    fn last_hash_test() {
+        let path = temp_dir().join("loom-client-identity-test");
+        let _ = remove_file(&path);
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14342".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-k".into(),
+            path.to_str().unwrap().into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let mut file = File::open(&path).expect("identity");
+        let mut e = Vec::new();
+        file.read_to_end(&mut e).expect("read");
+        let id: Wallet = serde_json::from_slice(&e).expect("parse");
+        let node = to32b(id.pubkeys[0]);
+        //without -K the key the node reports first is pinned and checked
+        let mut cfg = cfg("127.0.0.1:14342");
+        let e = client::last_hash(&cfg).expect("last hash");
+        assert_eq!(e.pld.from, node);
+        assert_eq!(cfg.node.get(), Some(node));
+        assert!(cfg.last_hash.get().is_some());
+        let pins: HashMap<String, String> = client::load_map(&client::pin_file(&cfg));
+        assert_eq!(pins[&cfg.host], BASE32HEX.encode(&node));
+        //someone else's entry
+        cfg.node.set(Some([1; 32]));
+        cfg.last_hash.set(None);
+        assert_matches!(client::last_hash(&cfg).map(|_| ()), Err(Error::BadSignature));
+        //a signed entry replayed for another request
+        fake_node(14340, move |req| vec![req, e]);
+        cfg.host = "127.0.0.1:14340".into();
+        cfg.node.set(Some(node));
+        cfg.last_hash.set(None);
+        assert_matches!(client::last_hash(&cfg).map(|_| ()), Err(Error::BadSignature));
+        //a node that signs with another key than the one pinned for it
+        let mut other = Wallet::new();
+        other.add_keypair(Wallet::new_keypair());
+        fake_node(14350, move |req| {
+            let mut e = data::Message::default();
+            e.pld.kind = data::Kind::LastHash;
+            e.pld.from = to32b(other.pubkeys[0]);
+            e.pld.get_nonce_mut().nonce.copy_from_slice(&req.sig[..32]);
+            Wallet::sign((other.privkeys[0], other.pubkeys[0]), &mut e);
+            vec![req, e]
+        });
+        cfg.host = "127.0.0.1:14350".into();
+        cfg.node.set(None);
+        cfg.last_hash.set(None);
+        let mut pins = HashMap::new();
+        pins.insert(cfg.host.clone(), BASE32HEX.encode(&node));
+        client::save_map(&client::pin_file(&cfg), &pins).expect("pin");
+        assert_matches!(client::last_hash(&cfg).map(|_| ()), Err(Error::BadSignature));
+        assert_eq!(cfg.node.get(), Some(node));
+        t.shutdown().expect("success");
+        remove_file(&path).expect("cleanup");
+        remove_wallet(&cfg.wallet);
+    }
+
+    /// poll the free balance of `key` until it is `amount`
+    fn wait_balance(cfg: &client::Cfg, key: [u8; 32], amount: u64) {
+        let s = net::socket().expect("socket");
+        let addr = client::resolve(&cfg.host).expect("resolve");
+        for _ in 0..100 {
+            let m = net::request(&s, &Wallet::query(key), addr, &cfg.retry).expect("query");
+            if m.pld.get_query().amount == amount {
+                return;
+            }
+            sleep(Duration::from_millis(50));
+        }
+        panic!("balance never reached {}", amount);
+    }
+
+    #[test]
+    fn compact_transfer_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14341".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let cfg = cfg("127.0.0.1:14341");
+        let addr: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
+        let to = [1u8; 32];
+        let other = BASE32HEX.encode(&to);
+        //compact transfers need the destination to exist already
+        client::transfer(&cfg, pass(), addr.clone(), other.clone(), 10, false).expect("transfer");
+        wait_balance(&cfg, to, 10);
+        client::transfer(&cfg, pass(), addr.clone(), other.clone(), 5, true).expect("compact");
+        wait_balance(&cfg, to, 15);
+        let mut ixs = client::load_indexes(&cfg);
+        assert_eq!(ixs.len(), 2);
+        //a stale cached index is answered, fetched again and the transfer resent
+        let bad = ixs[&addr] + 1;
+        ixs.insert(other.clone(), bad);
+        client::save_indexes(&cfg, &ixs).expect("save");
+        client::transfer(&cfg, pass(), addr.clone(), other.clone(), 5, true).expect("compact");
+        wait_balance(&cfg, to, 20);
+        assert_ne!(client::load_indexes(&cfg)[&other], bad);
+        t.shutdown().expect("success");
+        remove_wallet(&cfg.wallet);
+    }
+
+    #[test]
+    fn history_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14348".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let w = wallet("loom-client-14348");
+
+        let addr: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
+        let args = vec![
+            "loom".into(),
+            "-W".into(),
+            w.clone(),
+            "-H".into(),
+            "127.0.0.1:14348".into(),
+            "--history".into(),
+            "-f".into(),
+            addr,
+        ];
+        client::run(args, pass());
+        t.shutdown().expect("success");
+        remove_wallet(&w);
+    }
+
+    #[test]
+    fn dry_run_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "14349".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let w = wallet("loom-client-14349");
+
+        let from: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
+        let to: String = "SFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
+        let args = vec![
+            "loom".into(),
+            "-W".into(),
+            w.clone(),
+            "-H".into(),
+            "127.0.0.1:14349".into(),
+            "-x".into(),
+            "--dry-run".into(),
+            "-f".into(),
+            from.clone(),
+            "-t".into(),
+            to.clone(),
+            "-a".into(),
+            "100".into(),
+        ];
+        client::run(args, pass());
+        let mut cfg = cfg("127.0.0.1:14349");
+        cfg.wallet = w;
+        let r = client::dry_run(&cfg, pass(), from.clone(), to.clone(), 100).expect("dry run");
+        assert_eq!(r.pld.kind, data::Kind::Simulated);
+        assert_eq!(r.pld.state, data::State::Deposited);
+        let res = r.pld.get_result();
+        assert_eq!((res.from, res.to), (1000000000 - 100 - 1, 100));
+        //nothing was charged or written
+        let bals = client::query(&cfg, vec![from, to]).expect("query");
+        assert_eq!(bals, vec![Some(1000000000), None]);
+        t.shutdown().expect("success");
+        remove_wallet(&cfg.wallet);
+    }
+
+    #[test]
+    fn balance_timeout_test() {
+        let addr: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
+        //nothing listens on the port
+        let mut cfg = cfg("127.0.0.1:14347");
+        cfg.retry.timeout = Duration::from_millis(10);
+        cfg.retry.retries = 1;
+        let rv = client::balance(&cfg, pass(), addr.clone(), addr);
+        assert_matches!(rv, Err(Error::Timeout));
+        remove_wallet(&cfg.wallet);
     }
 
     #[test]
@@ -255,6 +1004,7 @@ mod tests {
             "testdata/test_accounts.json".into(),
         ];
         let mut t = daemon::run(args).expect("daemon load");
+        let w = wallet("loom-client-14345");
 
         let from: String = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====".into();
         assert!(BASE32HEX.decode(from.as_bytes()).is_ok());
@@ -263,7 +1013,7 @@ mod tests {
         let args = vec![
             "loom".into(),
             "-W".into(),
-            "testdata/loom.wallet".into(),
+            w.clone(),
             "-H".into(),
             "127.0.0.1:14345".into(),
             "-x".into(),
@@ -276,5 +1026,6 @@ mod tests {
         ];
         client::run(args, pass());
         t.shutdown().expect("success");
+        remove_wallet(&w);
     }
 }
@@ -1,72 +1,434 @@
 use state;
+use history;
+use limit;
+use priority;
 use data;
 use serde_json;
 
 use std::sync::{Arc, Mutex};
 use std::io::Read;
-use result::Result;
-use reader::Reader;
-use std::fs::File;
+use result::{from_option, Error, Result};
+use genesis::Genesis;
+use wallet::Wallet;
+use net;
+use std::cmp::min;
+use reader::{Backpressure, PoolStats, Reader, DEFAULT_POOL};
+use std::fs::{rename, File};
+use std::io::Write;
+use std::thread::{sleep, spawn};
+use std::time::{Duration, Instant};
+use nix::sys::signal::{SigSet, Signal};
 use std::mem::transmute;
+use std::path::PathBuf;
 use getopts::Options;
 use std::string::String;
-use otp::{Port, OTP};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use otp::{Data, Metrics, Port, Restart, OTP};
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!("Usage: {} FILE [options]", program);
     print!("{}", opts.usage(&brief));
 }
 
-fn loomd(testnet: Option<String>, port: u16) -> Result<OTP> {
-    let state = match testnet {
-        Some(f) => state_from_file(&f).and_then(|x| Ok(Arc::new(Mutex::new(x))))?,
-        None => Arc::new(Mutex::new(state::State::new(1024))),
+struct Cfg {
+    testnet: Option<String>,
+    snapshot: Option<String>,
+    addr: SocketAddr,
+    readers: usize,
+    pool: usize,
+    backpressure: Backpressure,
+    metrics: Option<Duration>,
+    reclaim: state::Reclaim,
+    capacity: usize,
+    data: Option<String>,
+    history: usize,
+    min_fee: Option<u64>,
+    genesis: Option<String>,
+    peer: Option<String>,
+    query_rate: u64,
+    audit: bool,
+    identity: Option<String>,
+}
+
+/// a running node, `join` waits for `Port::Main` and shuts down gracefully
+pub struct Loomd {
+    otp: OTP,
+    state: Arc<Mutex<state::State>>,
+    snapshot: Option<String>,
+    execute: Port,
+    readers: Vec<Arc<Reader>>,
+}
+
+impl Loomd {
+    /// post to `Port::Main` when one of `sigs` arrives, `sigs` must already
+    /// be blocked, see `block_signals`
+    pub fn on_signals(&self, sigs: SigSet) {
+        let ports = self.otp.ports();
+        spawn(move || {
+            if let Ok(s) = sigs.wait() {
+                info!("got {:?}, shutting down", s);
+                let _ = OTP::send(&ports, Port::Main, Data::Signal);
+            }
+        });
+    }
+    pub fn join(&mut self) -> Result<()> {
+        self.otp.wait()?;
+        self.stop()
+    }
+    /// stop intake, drain the State and Sender queues and write the snapshot
+    pub fn stop(&mut self) -> Result<()> {
+        let order = [
+            Port::Reader,
+            Port::State,
+            self.execute,
+            Port::Recycle,
+            Port::Sender,
+        ];
+        let mut rv = self.otp.drain(&order);
+        //a failed flush must not cost the snapshot, keep the first error
+        if let Ok(s) = self.state.lock() {
+            rv = rv.and(s.flush());
+        }
+        if let Some(ref path) = self.snapshot {
+            match self.state.lock() {
+                Ok(s) => rv = rv.and(state_to_file(&s, path)),
+                Err(_) => error!("state failed, not writing snapshot {:?}", path),
+            }
+        }
+        rv
+    }
+    /// snapshot of the per-port counters
+    pub fn metrics(&self) -> Vec<Metrics> {
+        self.otp.metrics()
+    }
+    /// buffer pool counters of every reader
+    pub fn pools(&self) -> Vec<PoolStats> {
+        self.readers.iter().map(|r| r.stats()).collect()
+    }
+    /// stop every thread without draining
+    pub fn shutdown(&mut self) -> Result<()> {
+        let rv = self.otp.shutdown();
+        //let go of the sockets so the port can be bound again
+        self.readers.clear();
+        rv
+    }
+}
+
+fn log_metrics(ms: &[Metrics], pools: &[PoolStats]) {
+    for (i, p) in pools.iter().enumerate() {
+        info!(
+            "reader {} pool {} of {} free {} dropped {} blocked {}",
+            i, p.allocated, p.max, p.free, p.dropped, p.blocked
+        );
+    }
+    for m in ms {
+        info!(
+            "{} sent {} handled {} depth {} p50 {:?} p99 {:?}",
+            m.name,
+            m.sent,
+            m.handled,
+            m.depth,
+            m.quantile(0.5),
+            m.quantile(0.99)
+        );
+    }
+}
+
+/// block SIGINT and SIGTERM for the calling thread and the threads it spawns
+/// from now on, call it before `run` so `Loomd::on_signals` gets them
+pub fn block_signals() -> Result<SigSet> {
+    let mut sigs = SigSet::empty();
+    sigs.add(Signal::SIGINT);
+    sigs.add(Signal::SIGTERM);
+    sigs.thread_block()?;
+    Ok(sigs)
+}
+
+fn loomd(cfg: Cfg) -> Result<Loomd> {
+    let mut s = match cfg.data {
+        Some(ref d) => state::State::open(PathBuf::from(d), cfg.capacity)?,
+        None => state::State::new(cfg.capacity),
+    };
+    let genesis = match cfg.genesis {
+        Some(ref f) => Some(Genesis::from_file(f)?),
+        None => None,
+    };
+    let (hash, network_id) = match genesis {
+        Some(ref g) => (g.hash()?, g.network_id),
+        None => ([0u8; 32], 0),
+    };
+    if let Some(ref peer) = cfg.peer {
+        check_peer(resolve(peer)?, &hash, network_id)?;
+    }
+    //the genesis and testnet accounts only seed an empty table
+    if let Some(ref g) = genesis {
+        if s.used() == 0 {
+            s.add_list(&g.accounts()?)?;
+        }
+    }
+    if let Some(ref f) = cfg.testnet {
+        if s.used() == 0 {
+            s.add_list(&accounts_from_file(f)?)?;
+        }
+    }
+    s.set_genesis(hash, network_id);
+    s.set_query_rate(cfg.query_rate);
+    s.set_reclaim(cfg.reclaim);
+    s.set_history(cfg.history);
+    s.set_audit(cfg.audit);
+    let id = identity(&cfg.identity)?;
+    s.set_identity(id.clone());
+    let fee = genesis.as_ref().map(|g| g.fee).unwrap_or(0);
+    s.set_min_fee(cfg.min_fee.unwrap_or(fee));
+    s.reserve(cfg.capacity)?;
+    let state = Arc::new(Mutex::new(s));
+    let readers: Vec<Arc<Reader>> = if cfg.readers > 1 {
+        Reader::reuseport(cfg.addr, cfg.readers)?
+            .into_iter()
+            .map(Arc::new)
+            .collect()
+    } else {
+        vec![Arc::new(Reader::bind(cfg.addr)?)]
     };
-    let reader = Reader::new(port).and_then(|x| Ok(Arc::new(x)))?;
-    let sender = reader.sender()?;
+    for r in readers.iter() {
+        r.set_pool(cfg.pool, cfg.backpressure);
+    }
+    let mut sender = readers[0].sender()?;
+    sender.set_identity(id);
     let mut o = OTP::new();
-    let a_reader = reader.clone();
-    o.source(Port::Reader, move |p| a_reader.run(p))?;
-    let b_reader = reader.clone();
+    for r in readers.iter() {
+        let a_reader = r.clone();
+        o.source(Port::Reader, move |p| a_reader.run(p))?;
+    }
+    let a_readers = readers.clone();
     o.listen(Port::Recycle, move |_p, d| {
-        b_reader.recycle(d);
+        Reader::recycle_to(&a_readers, d);
         Ok(())
     })?;
     o.listen(Port::Sender, move |_p, d| sender.run(d))?;
+    //the readers post to Port::State, which only queues the batches by fee
+    //until State is free to run the next one on `execute`
+    let queue = Arc::new(priority::Queue::new());
+    let execute = o.register("execute")?;
+    let a_queue = queue.clone();
+    o.listen(Port::State, move |p, d| a_queue.push(p, execute, d))?;
     let a_state = state.clone();
-    o.listen(Port::State, move |p, d| a_state.lock().unwrap().run(p, d))?;
-    return Ok(o);
+    //a failed batch can leave the accounts half updated, so stop the node
+    o.supervise(execute, Restart::StopAll)?;
+    o.listen(execute, move |p, _d| match queue.pop() {
+        Some(m) => a_state.lock().unwrap().run(p, Data::SharedMessages(m)),
+        None => Ok(()),
+    })?;
+    let tick_rate = genesis.as_ref().map(|g| g.tick_rate).unwrap_or(0);
+    if tick_rate > 0 {
+        let port = o.register("poh")?;
+        let period = Duration::from_nanos(1_000_000_000 / tick_rate);
+        let next = Mutex::new(Instant::now() + period);
+        let a_state = state.clone();
+        o.source(port, move |_p| {
+            sleep(min(period, Duration::from_millis(100)));
+            let mut next = next.lock().unwrap();
+            let mut num = 0;
+            while *next <= Instant::now() {
+                *next += period;
+                num += 1;
+            }
+            if num > 0 {
+                a_state.lock().unwrap().tick(num);
+            }
+            Ok(())
+        })?;
+    }
+    if let Some(period) = cfg.metrics {
+        let port = o.register("metrics")?;
+        let last = Mutex::new(Instant::now());
+        let pools = readers.clone();
+        //short naps so the source notices shutdown
+        o.source(port, move |p| {
+            sleep(Duration::from_millis(100));
+            let mut last = last.lock().unwrap();
+            if last.elapsed() >= period {
+                *last = Instant::now();
+                let stats: Vec<PoolStats> = pools.iter().map(|r| r.stats()).collect();
+                log_metrics(&p.metrics(), &stats);
+            }
+            Ok(())
+        })?;
+    }
+    return Ok(Loomd {
+        otp: o,
+        state: state,
+        snapshot: cfg.snapshot,
+        execute: execute,
+        readers: readers,
+    });
+}
+
+fn resolve(host: &str) -> Result<SocketAddr> {
+    let mut addrs = host.to_socket_addrs()?;
+    from_option(addrs.next())
 }
 
-#[derive(Deserialize)]
+/// ask the node at `peer` for its genesis, a different one is
+/// `Error::GenesisMismatch`
+pub fn check_peer(peer: SocketAddr, hash: &[u8; 32], network_id: u32) -> Result<()> {
+    let mut msg = data::Message::default();
+    msg.pld.kind = data::Kind::GetGenesis;
+    let s = net::socket()?;
+    let rmsg = net::request(&s, &msg, peer, &net::Retry::default())?;
+    let g = rmsg.pld.get_gen();
+    if g.hash != *hash || g.network_id != network_id {
+        error!("peer {:?} is on network {:?}", peer, g.network_id);
+        return Err(Error::GenesisMismatch);
+    }
+    Ok(())
+}
+
+/// the node's key pair, kept unencrypted in `path` and made the first time,
+/// without `path` there is a new one every start
+fn identity(path: &Option<String>) -> Result<Wallet> {
+    if let Some(ref p) = *path {
+        if let Ok(mut file) = File::open(p) {
+            let mut e = Vec::new();
+            let _sz = file.read_to_end(&mut e)?;
+            let w: Wallet = serde_json::from_slice(&e)?;
+            if w.pubkeys.is_empty() {
+                return Err(Error::Corrupt);
+            }
+            return Ok(w);
+        }
+    }
+    let mut w = Wallet::new();
+    w.add_keypair(Wallet::new_keypair());
+    if let Some(ref p) = *path {
+        let mut file = File::create(p)?;
+        file.write_all(&serde_json::to_vec(&w)?)?;
+    }
+    Ok(w)
+}
+
+#[derive(Serialize, Deserialize)]
 struct TestAccount {
     pub pubkey: [u64; 4],
-    pub balance: u32,
+    pub balance: u64,
 }
 
-fn state_from_file(f: &str) -> Result<state::State> {
+fn accounts_from_file(f: &str) -> Result<Vec<data::Account>> {
     let mut file = File::open(f)?;
     let mut e = Vec::new();
     let _sz = file.read_to_end(&mut e)?;
     let v: Vec<TestAccount> = serde_json::from_slice(&e)?;
-    let acc: Vec<data::Account> = v.iter()
+    let acc = v.iter()
         .map(|a| {
             let pk = unsafe { transmute::<[u64; 4], [u8; 32]>(a.pubkey) };
             data::Account {
                 from: pk,
-                balance: a.balance as u64,
+                balance: a.balance,
             }
         })
         .collect();
-    state::State::from_list(&acc)
+    Ok(acc)
 }
 
-pub fn run(args: Vec<String>) -> Option<OTP> {
+/// write the accounts in the testnet format so `-t` can load them back
+fn state_to_file(s: &state::State, f: &str) -> Result<()> {
+    let v: Vec<TestAccount> = s.to_list()
+        .iter()
+        .map(|a| TestAccount {
+            pubkey: unsafe { transmute::<[u8; 32], [u64; 4]>(a.from) },
+            balance: a.balance,
+        })
+        .collect();
+    let tmp = format!("{}.tmp", f);
+    {
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(&v)?)?;
+        file.sync_all()?;
+    }
+    rename(&tmp, f)?;
+    Ok(())
+}
+
+pub fn run(args: Vec<String>) -> Option<Loomd> {
     let program = args[0].clone();
     let mut opts = Options::new();
     opts.optflag("h", "help", "print this help menu");
     opts.optopt("l", "", "Run as a Loom with a listen port", "PORT");
     opts.optopt("t", "", "testnet accounts", "FILE");
+    opts.optopt(
+        "g",
+        "",
+        "genesis accounts and network parameters",
+        "FILE",
+    );
+    opts.optopt(
+        "P",
+        "",
+        "refuse to start unless the node at HOST:PORT has the same genesis",
+        "HOST:PORT",
+    );
+    opts.optopt(
+        "s",
+        "",
+        "write the accounts to FILE on shutdown, load it back with -t",
+        "FILE",
+    );
+    opts.optopt("b", "", "bind address instead of 0.0.0.0", "ADDRESS");
+    opts.optopt(
+        "r",
+        "",
+        "number of reader threads sharing the port with SO_REUSEPORT",
+        "NUM",
+    );
+    opts.optopt("p", "", "maximum number of buffers per reader", "NUM");
+    opts.optflag(
+        "B",
+        "",
+        "block readers instead of dropping packets when out of buffers",
+    );
+    opts.optopt("m", "", "log per-port metrics every SECS seconds", "SECS");
+    opts.optflag("z", "", "remove accounts once their balance is 0");
+    opts.optopt(
+        "k",
+        "",
+        "node identity key, created if missing, without it a new one every start",
+        "FILE",
+    );
+    opts.optflag(
+        "A",
+        "",
+        "check the supply after every batch and stop if the balances don't add up",
+    );
+    opts.optopt(
+        "c",
+        "",
+        "initial size of the accounts table, default 1024",
+        "NUM",
+    );
+    opts.optopt(
+        "d",
+        "",
+        "keep the accounts in memory mapped files in DIR",
+        "DIR",
+    );
+    opts.optopt(
+        "y",
+        "",
+        "history entries kept per account, default 64, 0 disables it",
+        "NUM",
+    );
+    opts.optopt(
+        "f",
+        "",
+        "reject messages paying less than FEE, default from the genesis or 0",
+        "FEE",
+    );
+    opts.optopt(
+        "q",
+        "",
+        "answer up to RATE free queries a second per address, 0 for no limit, default 100",
+        "RATE",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -78,7 +440,61 @@ pub fn run(args: Vec<String>) -> Option<OTP> {
     if matches.opt_str("l").is_some() {
         let ports = matches.opt_str("l").expect("missing loom port");
         let port = ports.parse().expect("expecting u16 number for port");
-        let daemon = loomd(matches.opt_str("t"), port).expect("loomd");
+        let ip: IpAddr = matches
+            .opt_str("b")
+            .unwrap_or("0.0.0.0".to_string())
+            .parse()
+            .expect("expecting an ip address to bind");
+        let num_readers = matches
+            .opt_str("r")
+            .map(|r| r.parse().expect("expecting a number of readers"))
+            .unwrap_or(1);
+        let pool = matches
+            .opt_str("p")
+            .map(|p| p.parse().expect("expecting a number of buffers"))
+            .unwrap_or(DEFAULT_POOL);
+        let backpressure = if matches.opt_present("B") {
+            Backpressure::Block
+        } else {
+            Backpressure::Drop
+        };
+        let cfg = Cfg {
+            testnet: matches.opt_str("t"),
+            snapshot: matches.opt_str("s"),
+            addr: SocketAddr::new(ip, port),
+            readers: num_readers,
+            pool: pool,
+            backpressure: backpressure,
+            metrics: matches
+                .opt_str("m")
+                .map(|m| Duration::new(m.parse().expect("expecting seconds"), 0)),
+            reclaim: if matches.opt_present("z") {
+                state::Reclaim::Remove
+            } else {
+                state::Reclaim::Keep
+            },
+            capacity: matches
+                .opt_str("c")
+                .map(|c| c.parse().expect("expecting a table size"))
+                .unwrap_or(1024),
+            data: matches.opt_str("d"),
+            history: matches
+                .opt_str("y")
+                .map(|y| y.parse().expect("expecting a number of entries"))
+                .unwrap_or(history::DEFAULT_MAX),
+            min_fee: matches
+                .opt_str("f")
+                .map(|f| f.parse().expect("expecting a fee")),
+            genesis: matches.opt_str("g"),
+            peer: matches.opt_str("P"),
+            query_rate: matches
+                .opt_str("q")
+                .map(|q| q.parse().expect("expecting a rate"))
+                .unwrap_or(limit::DEFAULT_RATE),
+            audit: matches.opt_present("A"),
+            identity: matches.opt_str("k"),
+        };
+        let daemon = loomd(cfg).expect("loomd");
         return Some(daemon);
     } else {
         print_usage(&program, opts);
@@ -89,27 +505,37 @@ pub fn run(args: Vec<String>) -> Option<OTP> {
 #[cfg(test)]
 mod tests {
     use daemon;
-    use net;
     use data;
+    use net;
     use wallet;
-    use result::Result;
-    use std::net::UdpSocket;
+    use genesis::Genesis;
+    use result::{Error, Result};
+    use std::net::{SocketAddr, UdpSocket};
     use std::mem::transmute;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+    use proxy::{Policy, Proxy, Stats};
+    use otp::{Data, Port, OTP};
+    use state;
+    use std::env::temp_dir;
+    use std::fs::{remove_dir_all, remove_file};
+    use std::thread::spawn;
+    use nix::sys::signal::{raise, Signal};
 
     fn check_balance(s: &UdpSocket, w: &wallet::Wallet, to: [u8; 32]) -> Result<u64> {
-        let mut num = 0;
         let addr = "127.0.0.1:24569".parse().expect("parse");
-        while num < 1 {
-            let msg = w.check_balance(0, to, 1);
-            net::send_to(&s, &[msg], &mut num, addr)?;
-        }
-        assert_eq!(num, 1);
-        let mut rmsgs = data::Messages::new();
-        rmsgs
-            .with_mut(|m, d| net::read_from(&s, m, d))
-            .expect("read rmsgs");
-        assert_eq!(rmsgs.data[0].0, 1);
-        Ok(rmsgs.msgs[0].pld.get_bal().amount)
+        check_balance_at(s, w, to, addr, 0)
+    }
+    fn check_balance_at(
+        s: &UdpSocket,
+        w: &wallet::Wallet,
+        to: [u8; 32],
+        addr: SocketAddr,
+        network: u32,
+    ) -> Result<u64> {
+        let msg = w.check_balance(0, to, 1, network);
+        let rmsg = net::request(&s, &msg, addr, &net::Retry::default())?;
+        Ok(rmsg.pld.get_bal().amount)
     }
     fn from_pk(d: [u64; 4]) -> [u8; 32] {
         unsafe { transmute::<[u64; 4], [u8; 32]>(d) }
@@ -127,6 +553,7 @@ mod tests {
             "24569".into(),
             "-t".into(),
             "testdata/test_accounts.json".into(),
+            "-A".into(),
         ];
         let mut t = daemon::run(args).expect("daemon load");
         let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
@@ -138,7 +565,7 @@ mod tests {
         let addr = "127.0.0.1:24569".parse().expect("parse");
         let mut num = 0;
         while num < 1 {
-            let msg = w.tx(0, to, 1000, 1);
+            let msg = w.tx(0, to, 1000, 1, 0);
             net::send_to(&s, &[msg], &mut num, addr).expect("write message");
         }
         let bto = check_balance(&s, &w, to).expect("check bal to");
@@ -148,6 +575,275 @@ mod tests {
         t.shutdown().expect("success");
     }
     #[test]
+    fn min_fee_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24563".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-f".into(),
+            "2".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let from = from_pk(w.pubkeys[0]);
+        let s = net::socket().expect("socket");
+        let addr = "127.0.0.1:24563".parse().expect("parse");
+        let retry = net::Retry::default();
+        let msg = w.check_balance(0, from, 1, 0);
+        let rmsg = net::request(&s, &msg, addr, &retry).expect("rejected");
+        assert_eq!(rmsg.pld.state, data::State::FeeTooLow);
+        let msg = w.check_balance(0, from, 2, 0);
+        let rmsg = net::request(&s, &msg, addr, &retry).expect("balance");
+        assert_eq!(rmsg.pld.state, data::State::Withdrawn);
+        assert_eq!(rmsg.pld.get_bal().amount, 1000000000 - 2);
+        t.shutdown().expect("success");
+    }
+    #[test]
+    fn lossy_transaction_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24566".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let kp = wallet::Wallet::new_keypair();
+        let to = from_pk(kp.1);
+        let addr = "127.0.0.1:24566".parse().expect("parse");
+        let mut policy = Policy::default();
+        policy.seed = 7;
+        policy.drop = 0.25;
+        policy.duplicate = 0.25;
+        policy.reorder = 0.5;
+        policy.delay = 0.5;
+        policy.max_delay = Duration::from_millis(20);
+        let mut p = Proxy::new(addr, policy).expect("proxy");
+        let s = net::socket().expect("socket");
+        for _ in 0..16 {
+            let mut num = 0;
+            while num < 1 {
+                let msg = w.tx(0, to, 250, 1, 0);
+                net::send_to(&s, &[msg], &mut num, p.addr()).expect("write message");
+            }
+        }
+        //transfers aren't answered, everything forwarded went upstream
+        let done = |st: Stats| st.received == 16 && st.forwarded == 16 - st.dropped + st.duplicated;
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done(p.stats()) && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+        assert!(done(p.stats()), "{:?}", p.stats());
+        p.shutdown().expect("proxy shutdown");
+        let stats = p.stats();
+        assert!(stats.dropped > 0);
+        assert!(stats.duplicated > 0);
+        //a duplicate is executed twice, there is no replay protection yet
+        let bto = check_balance_at(&s, &w, to, addr, 0).expect("check bal to");
+        assert_eq!(bto, 250 * stats.forwarded as u64);
+        t.shutdown().expect("success");
+    }
+    #[test]
+    fn readers_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24567".into(),
+            "-b".into(),
+            "127.0.0.1".into(),
+            "-r".into(),
+            "4".into(),
+            "-p".into(),
+            "8".into(),
+            "-B".into(),
+            "-m".into(),
+            "1".into(),
+            "-c".into(),
+            "4096".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let from = from_pk(w.pubkeys[0]);
+        let s = net::socket().expect("socket");
+        let addr = "127.0.0.1:24567".parse().expect("parse");
+        let bfrom = check_balance_at(&s, &w, from, addr, 0).expect("check bal from");
+        assert_eq!(bfrom, 1000000000 - 1);
+        let m = t.metrics();
+        assert!(m.iter().any(|m| m.name == "metrics"));
+        assert!(m.iter().any(|m| m.name == "state" && m.sent >= 1));
+        let pools = t.pools();
+        assert_eq!(pools.len(), 4);
+        assert!(pools.iter().all(|p| p.max == 8 && p.allocated <= p.max));
+        t.shutdown().expect("success");
+    }
+    #[test]
+    fn snapshot_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24565".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-s".into(),
+            "TESTSNAPSHOT".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let kp = wallet::Wallet::new_keypair();
+        let to = from_pk(kp.1);
+        let s = net::socket().expect("socket");
+        let addr = "127.0.0.1:24565".parse().expect("parse");
+        for _ in 0..16 {
+            let mut num = 0;
+            while num < 1 {
+                let msg = w.tx(0, to, 10, 1, 0);
+                net::send_to(&s, &[msg], &mut num, addr).expect("write message");
+            }
+        }
+        sleep(Duration::from_millis(100));
+        OTP::send(&t.otp.ports(), Port::Main, Data::Signal).expect("signal");
+        t.join().expect("join");
+        let list = daemon::accounts_from_file("TESTSNAPSHOT").expect("load snapshot");
+        remove_file("TESTSNAPSHOT").expect("remove");
+        assert_eq!(list.len(), 2);
+        let total: u64 = list.iter().map(|a| a.balance).sum();
+        assert_eq!(total, 1000000000 - 16);
+        assert!(list.iter().any(|a| a.from == to && a.balance == 160));
+    }
+    #[test]
+    fn data_dir_test() {
+        let dir = temp_dir().join("loom-daemon-data-test");
+        let _ = remove_dir_all(&dir);
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24564".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-d".into(),
+            dir.to_str().unwrap().into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let kp = wallet::Wallet::new_keypair();
+        let to = from_pk(kp.1);
+        let s = net::socket().expect("socket");
+        let addr = "127.0.0.1:24564".parse().expect("parse");
+        for _ in 0..4 {
+            let mut num = 0;
+            while num < 1 {
+                let msg = w.tx(0, to, 10, 1, 0);
+                net::send_to(&s, &[msg], &mut num, addr).expect("write message");
+            }
+        }
+        sleep(Duration::from_millis(100));
+        OTP::send(&t.otp.ports(), Port::Main, Data::Signal).expect("signal");
+        t.join().expect("join");
+        drop(t);
+        //the accounts come back from the directory without a snapshot
+        let st = state::State::open(dir.clone(), 1024).expect("reopen");
+        let list = st.to_list();
+        assert_eq!(list.len(), 2);
+        let total: u64 = list.iter().map(|a| a.balance).sum();
+        assert_eq!(total, 1000000000 - 4);
+        assert!(list.iter().any(|a| a.from == to && a.balance == 40));
+        drop(st);
+        remove_dir_all(&dir).expect("cleanup");
+    }
+    #[test]
+    fn genesis_test() {
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24562".into(),
+            "-g".into(),
+            "testdata/genesis.json".into(),
+        ];
+        let mut t = daemon::run(args).expect("daemon load");
+        let ew = wallet::EncryptedWallet::from_file("testdata/loom.wallet").expect("test wallet");
+        let w = ew.decrypt("foobar".as_bytes()).expect("decrypt");
+        let from = from_pk(w.pubkeys[0]);
+        let s = net::socket().expect("socket");
+        let addr = "127.0.0.1:24562".parse().expect("parse");
+        let g = Genesis::from_file("testdata/genesis.json").expect("genesis");
+        let bal = check_balance_at(&s, &w, from, addr, g.network_id).expect("balance");
+        assert_eq!(bal, 1000000000 - 1);
+        //signed for another network
+        let msg = w.check_balance(0, from, 1, g.network_id + 1);
+        let rmsg = net::request(&s, &msg, addr, &net::Retry::default()).expect("rejected");
+        assert_eq!(rmsg.pld.state, data::State::WrongNetwork);
+        //signed by a client of another protocol version
+        let mut msg = w.check_balance(0, from, 1, g.network_id);
+        assert_eq!(msg.pld.version, data::VERSION);
+        msg.pld.version = data::VERSION - 1;
+        wallet::Wallet::sign((w.privkeys[0], w.pubkeys[0]), &mut msg);
+        let rmsg = net::request(&s, &msg, addr, &net::Retry::default()).expect("rejected");
+        assert_eq!(rmsg.pld.state, data::State::WrongVersion);
+        let hash = g.hash().expect("hash");
+        daemon::check_peer(addr, &hash, g.network_id).expect("same genesis");
+        assert_matches!(
+            daemon::check_peer(addr, &[0; 32], g.network_id),
+            Err(Error::GenesisMismatch)
+        );
+        assert_matches!(
+            daemon::check_peer(addr, &hash, g.network_id + 1),
+            Err(Error::GenesisMismatch)
+        );
+        t.shutdown().expect("success");
+    }
+    #[test]
+    fn identity_test() {
+        let path = temp_dir().join("loom-daemon-identity-test");
+        let _ = remove_file(&path);
+        let path = path.to_str().unwrap().to_string();
+        let args = vec![
+            "loomd".into(),
+            "-l".into(),
+            "24561".into(),
+            "-t".into(),
+            "testdata/test_accounts.json".into(),
+            "-k".into(),
+            path.clone(),
+        ];
+        let mut t = daemon::run(args.clone()).expect("daemon load");
+        let s = net::socket().expect("socket");
+        let addr = "127.0.0.1:24561".parse().expect("parse");
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::GetInfo;
+        let rmsg = net::request(&s, &msg, addr, &net::Retry::default()).expect("info");
+        assert_eq!(rmsg.pld.get_info().used, 1);
+        assert_eq!(rmsg.pld.get_info().supply, 1000000000);
+        assert_eq!(rmsg.pld.get_info().version, data::VERSION);
+        let key = rmsg.pld.from;
+        assert!(key != [0; 32]);
+        t.shutdown().expect("success");
+        //the same identity after a restart
+        let mut t = daemon::run(args).expect("daemon reload");
+        let rmsg = net::request(&s, &msg, addr, &net::Retry::default()).expect("info");
+        assert_eq!(rmsg.pld.from, key);
+        t.shutdown().expect("success");
+        remove_file(&path).expect("cleanup");
+    }
+    #[test]
+    fn block_signals_test() {
+        let t = spawn(|| {
+            let sigs = daemon::block_signals().expect("block");
+            raise(Signal::SIGTERM).expect("raise");
+            sigs.wait().expect("wait")
+        });
+        assert_eq!(t.join().unwrap(), Signal::SIGTERM);
+    }
+    #[test]
     fn realnet_test() {
         let args = vec!["loomd".into(), "-l".into(), "24568".into()];
         let mut t = daemon::run(args).expect("daemon load");
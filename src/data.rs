@@ -2,6 +2,7 @@
 //!
 //! TBD a lightweight serialization format.
 
+use std::mem::size_of;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::{Arc, RwLock};
 use hasht::{HashT, Key, Val};
@@ -20,11 +21,147 @@ pub struct GetBalance {
     pub amount: u64,
 }
 
+/// a transfer between existing accounts named by their index in the
+/// accounts table, `check` is `Key::start` of the destination key and
+/// `from_check` of the source, it goes on the wire as a `CompactTx`, the
+/// indexes are only valid until the next resize or reclaim moves the
+/// accounts, after that it is rejected with `State::StaleIndex`
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Compact {
+    pub from: u64,
+    pub to: u64,
+    pub check: u64,
+    pub amount: u64,
+    pub from_check: u64,
+}
+
+/// wire form of a `Kind::Compact`, a packet of them starts with
+/// `COMPACT_MAGIC` and fits `MAX_COMPACT` transfers where full messages fit
+/// `MAX_PACKET / size_of::<Message>()`, `sig` signs everything before it
+/// with the key at index `compact.from`
+#[derive(Copy, Clone)]
+#[repr(C)]
+pub struct CompactTx {
+    pub compact: Compact,
+    pub fee: u64,
+    pub lvh_count: u64,
+    pub version: u32,
+    pub network: u32,
+    pub sig: [u8; 64],
+}
+
+impl Default for CompactTx {
+    fn default() -> CompactTx {
+        CompactTx {
+            compact: Compact::default(),
+            fee: 0,
+            lvh_count: 0,
+            version: VERSION,
+            network: 0,
+            sig: [0u8; 64],
+        }
+    }
+}
+
+impl CompactTx {
+    /// the message the node runs, `pld.from` is left for `State` to fill in
+    /// from the table
+    pub fn to_message(&self) -> Message {
+        let mut m = Message::default();
+        m.pld.kind = Kind::Compact;
+        m.pld.fee = self.fee;
+        m.pld.lvh_count = self.lvh_count;
+        m.pld.version = self.version;
+        m.pld.network = self.network;
+        m.pld.data.compact = self.compact;
+        m.sig = self.sig;
+        m
+    }
+}
+
+/// asks for the index of `key`, the reply fills in `index`
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct GetIndex {
+    pub key: [u8; 32],
+    pub index: u64,
+}
+
+/// asks for up to `num` history entries of `key`, which must be `pld.from`,
+/// skipping the `start` newest ones, the reply fills in the number of
+/// entries that follow it
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct GetHistory {
+    pub key: [u8; 32],
+    pub start: u32,
+    pub num: u32,
+}
+
+/// a history entry, `pld.from` is the account, `pld.fee` the fee,
+/// `pld.lvh_count` the PoH count, `sig` the transfer's signature and
+/// `pld.state` is `Withdrawn` when the account paid and `Deposited` when it
+/// was paid
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct History {
+    pub counterparty: [u8; 32],
+    pub amount: u64,
+}
+
+/// reply to `Kind::Simulate`, the balances the transfer would leave,
+/// `pld.state` is `Deposited` if it would go through
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Simulated {
+    pub from: u64,
+    pub to: u64,
+}
+
+/// asks for the node's genesis, free and unsigned, the reply fills it in
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct GetGenesis {
+    pub hash: [u8; 32],
+    pub network_id: u32,
+}
+
+/// reply to `Kind::GetInfo`, `pld.from` is the node's identity key and
+/// `pld.lvh`, `pld.lvh_count` its latest PoH entry
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Info {
+    /// sum of all balances, saturated at `u64::max_value()`
+    pub supply: u64,
+    pub used: u64,
+    pub capacity: u64,
+    pub version: u32,
+    pub unused: u32,
+}
+
+/// data of `Kind::LastHash`, the first half of the `sig` of the
+/// `GetLastHash` it answers, so an old entry can't be replayed to a client
+#[derive(Copy, Clone, Default)]
+#[repr(C)]
+pub struct Nonce {
+    pub nonce: [u8; 32],
+    pub unused: u64,
+}
+
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub union MessageData {
     pub tx: Transaction,
     pub bal: GetBalance,
+    pub compact: Compact,
+    pub idx: GetIndex,
+    pub hist: GetHistory,
+    pub entry: History,
+    pub sim: Simulated,
+    pub gen: GetGenesis,
+    pub info: Info,
+    pub nonce: Nonce,
 }
 
 impl Default for MessageData {
@@ -41,6 +178,27 @@ pub enum Kind {
     Invalid,
     Transaction,
     GetBalance,
+    Compact,
+    GetIndex,
+    GetHistory,
+    History,
+    /// a `Transaction` that is only tried, nothing is charged or written,
+    /// rate limited per address like the free queries
+    Simulate,
+    Simulated,
+    GetGenesis,
+    /// free and unsigned balance of `key`, uses `GetBalance`, `pld.state`
+    /// of the reply is `Withdrawn` if the account exists, `sig` only has to
+    /// tell the queries of a client apart
+    Query,
+    /// free and unsigned, asks the node about itself, see `Info`
+    GetInfo,
+    /// free and unsigned, asks for the node's latest PoH entry, the reply is
+    /// followed by a `LastHash` in the same packet
+    GetLastHash,
+    /// PoH entry in `pld.lvh` and `pld.lvh_count`, signed together with the
+    /// `Nonce` of the request by the node whose identity key is `pld.from`
+    LastHash,
 }
 
 impl Default for Kind {
@@ -62,6 +220,15 @@ pub enum State {
     Unknown,
     Withdrawn,
     Deposited,
+    /// rejected without running, the fee is below the node's minimum
+    FeeTooLow,
+    /// rejected without running, signed for another network
+    WrongNetwork,
+    /// rejected without running, the indexes of a `Kind::Compact` no longer
+    /// point at its accounts and have to be looked up again
+    StaleIndex,
+    /// rejected without running, `pld.version` is not this node's `VERSION`
+    WrongVersion,
 }
 impl Copy for State {}
 
@@ -78,7 +245,21 @@ impl Default for State {
 }
 pub const MAX_PACKET: usize = 1024 * 4;
 
-#[derive(Default, Copy, Clone)]
+/// first four bytes of a packet of `CompactTx`, the packet size can't be a
+/// multiple of `size_of::<Message>()` either
+pub const COMPACT_MAGIC: u32 = 0x636d_7074;
+
+/// number of `CompactTx` that fit in a packet
+pub const MAX_COMPACT: usize = (MAX_PACKET - size_of::<u32>()) / size_of::<CompactTx>();
+
+/// protocol version of this build, bumped whenever the wire layout changes,
+/// signed messages of another version are answered with
+/// `State::WrongVersion`
+pub const VERSION: u32 = 2;
+
+/// `Default` stamps `VERSION`, so every message built from it is signed
+/// for this build's layout
+#[derive(Copy, Clone)]
 #[repr(C)]
 pub struct Payload {
     pub from: [u8; 32],
@@ -87,9 +268,30 @@ pub struct Payload {
     pub fee: u64,
     pub data: MessageData,
     pub version: u32,
+    /// `Genesis::network_id` of the network the message was signed for
+    pub network: u32,
     pub kind: Kind,
     pub state: State, //zero when signed
     pub unused: u16,  //zero when signed
+    pub reserved: u32,
+}
+
+impl Default for Payload {
+    fn default() -> Payload {
+        Payload {
+            from: [0u8; 32],
+            lvh: [0u8; 32],
+            lvh_count: 0,
+            fee: 0,
+            data: MessageData::default(),
+            version: VERSION,
+            network: 0,
+            kind: Kind::default(),
+            state: State::default(),
+            unused: 0,
+            reserved: 0,
+        }
+    }
 }
 
 impl Payload {
@@ -109,6 +311,95 @@ impl Payload {
         assert_eq!(self.kind, Kind::GetBalance);
         unsafe { &mut self.data.bal }
     }
+    pub fn get_compact(&self) -> &Compact {
+        assert_eq!(self.kind, Kind::Compact);
+        unsafe { &self.data.compact }
+    }
+    pub fn get_compact_mut(&mut self) -> &mut Compact {
+        assert_eq!(self.kind, Kind::Compact);
+        unsafe { &mut self.data.compact }
+    }
+    pub fn get_idx(&self) -> &GetIndex {
+        assert_eq!(self.kind, Kind::GetIndex);
+        unsafe { &self.data.idx }
+    }
+    pub fn get_idx_mut(&mut self) -> &mut GetIndex {
+        assert_eq!(self.kind, Kind::GetIndex);
+        unsafe { &mut self.data.idx }
+    }
+    pub fn get_hist(&self) -> &GetHistory {
+        assert_eq!(self.kind, Kind::GetHistory);
+        unsafe { &self.data.hist }
+    }
+    pub fn get_hist_mut(&mut self) -> &mut GetHistory {
+        assert_eq!(self.kind, Kind::GetHistory);
+        unsafe { &mut self.data.hist }
+    }
+    pub fn get_entry(&self) -> &History {
+        assert_eq!(self.kind, Kind::History);
+        unsafe { &self.data.entry }
+    }
+    pub fn get_entry_mut(&mut self) -> &mut History {
+        assert_eq!(self.kind, Kind::History);
+        unsafe { &mut self.data.entry }
+    }
+    /// the transfer a `Kind::Simulate` would make
+    pub fn get_sim(&self) -> &Transaction {
+        assert_eq!(self.kind, Kind::Simulate);
+        unsafe { &self.data.tx }
+    }
+    pub fn get_sim_mut(&mut self) -> &mut Transaction {
+        assert_eq!(self.kind, Kind::Simulate);
+        unsafe { &mut self.data.tx }
+    }
+    pub fn get_result(&self) -> &Simulated {
+        assert_eq!(self.kind, Kind::Simulated);
+        unsafe { &self.data.sim }
+    }
+    pub fn get_result_mut(&mut self) -> &mut Simulated {
+        assert_eq!(self.kind, Kind::Simulated);
+        unsafe { &mut self.data.sim }
+    }
+    pub fn get_gen(&self) -> &GetGenesis {
+        assert_eq!(self.kind, Kind::GetGenesis);
+        unsafe { &self.data.gen }
+    }
+    pub fn get_gen_mut(&mut self) -> &mut GetGenesis {
+        assert_eq!(self.kind, Kind::GetGenesis);
+        unsafe { &mut self.data.gen }
+    }
+    pub fn get_query(&self) -> &GetBalance {
+        assert_eq!(self.kind, Kind::Query);
+        unsafe { &self.data.bal }
+    }
+    pub fn get_query_mut(&mut self) -> &mut GetBalance {
+        assert_eq!(self.kind, Kind::Query);
+        unsafe { &mut self.data.bal }
+    }
+    pub fn get_info(&self) -> &Info {
+        assert_eq!(self.kind, Kind::GetInfo);
+        unsafe { &self.data.info }
+    }
+    pub fn get_info_mut(&mut self) -> &mut Info {
+        assert_eq!(self.kind, Kind::GetInfo);
+        unsafe { &mut self.data.info }
+    }
+    pub fn get_nonce(&self) -> &Nonce {
+        assert_eq!(self.kind, Kind::LastHash);
+        unsafe { &self.data.nonce }
+    }
+    pub fn get_nonce_mut(&mut self) -> &mut Nonce {
+        assert_eq!(self.kind, Kind::LastHash);
+        unsafe { &mut self.data.nonce }
+    }
+    /// free queries skip the fee and are rate limited per address instead
+    pub fn is_free(&self) -> bool {
+        self.kind == Kind::GetGenesis
+            || self.kind == Kind::Query
+            || self.kind == Kind::GetInfo
+            || self.kind == Kind::GetLastHash
+            || self.kind == Kind::Simulate
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -162,6 +453,8 @@ pub type AccountT = HashT<[u8; 32], Account>;
 pub struct Messages {
     pub msgs: Vec<Message>,
     pub data: Vec<(usize, SocketAddr)>,
+    /// id of the reader whose pool the buffer goes back to, 0 for none
+    pub owner: usize,
 }
 
 impl Messages {
@@ -169,6 +462,7 @@ impl Messages {
         Messages {
             msgs: vec![Message::default(); 1024],
             data: vec![Self::def_data(); 1024],
+            owner: 0,
         }
     }
     pub fn def_data() -> (usize, SocketAddr) {
@@ -195,13 +489,36 @@ pub type SharedMessages = Arc<RwLock<Messages>>;
 #[cfg(test)]
 mod tests {
     use data;
+    use std::mem::size_of;
     #[test]
     fn data_test() {
         let _ = data::Transaction::default().clone();
         let _ = data::GetBalance::default().clone();
+        let _ = data::Compact::default().clone();
+        let _ = data::CompactTx::default().clone();
+        let _ = data::GetIndex::default().clone();
+        let _ = data::GetHistory::default().clone();
+        let _ = data::History::default().clone();
+        let _ = data::Simulated::default().clone();
+        let _ = data::GetGenesis::default().clone();
+        let _ = data::Info::default().clone();
+        let _ = data::Nonce::default().clone();
         let _ = data::MessageData::default().clone();
         let _ = data::Kind::default().clone();
         let _ = data::State::default().clone();
         let _ = data::Messages::new();
     }
+    #[test]
+    fn compact_size_test() {
+        let sz = size_of::<data::Message>();
+        //a new layout needs a new `VERSION`
+        assert_eq!(size_of::<data::Payload>(), 136);
+        assert_eq!(data::VERSION, 2);
+        assert_eq!(size_of::<data::CompactTx>(), 128);
+        assert!(data::MAX_COMPACT > data::MAX_PACKET / sz);
+        //a packet of compact transfers never looks like one of messages
+        for n in 1..data::MAX_COMPACT + 1 {
+            assert!((4 + n * size_of::<data::CompactTx>()) % sz != 0);
+        }
+    }
 }
@@ -0,0 +1,150 @@
+//! genesis configuration, the accounts and network parameters a network
+//! starts from
+//!
+//! keys are BASE32HEX like `loom -l` prints them, the genesis hash covers
+//! everything in the file and nodes with a different hash are on another
+//! network
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use data;
+use data_encoding::BASE32HEX;
+use result::{Error, Result};
+use serde_json;
+use std::fs::File;
+use std::io::{Read, Write};
+
+/// a tick every nanosecond, the finest period a node can tick at
+pub const MAX_TICK_RATE: u64 = 1_000_000_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Account {
+    pub key: String,
+    pub balance: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Genesis {
+    pub network_id: u32,
+    /// minimum fee per message
+    pub fee: u64,
+    /// PoH ticks per second, 0 only ticks for transfers, at most
+    /// `MAX_TICK_RATE`
+    pub tick_rate: u64,
+    pub accounts: Vec<Account>,
+}
+
+impl Genesis {
+    pub fn from_file(path: &str) -> Result<Genesis> {
+        let mut file = File::open(path)?;
+        let mut e = Vec::new();
+        let _sz = file.read_to_end(&mut e)?;
+        let g: Genesis = serde_json::from_slice(&e)?;
+        if g.tick_rate > MAX_TICK_RATE {
+            return Err(Error::Corrupt);
+        }
+        Ok(g)
+    }
+    pub fn to_file(&self, path: &str) -> Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+    /// the accounts with their keys decoded, a bad key is `Error::Corrupt`
+    pub fn accounts(&self) -> Result<Vec<data::Account>> {
+        self.accounts
+            .iter()
+            .map(|a| {
+                let k = BASE32HEX
+                    .decode(a.key.as_bytes())
+                    .or_else(|_| Err(Error::Corrupt))?;
+                if k.len() != 32 {
+                    return Err(Error::Corrupt);
+                }
+                let mut from = [0u8; 32];
+                from.copy_from_slice(&k);
+                Ok(data::Account {
+                    from: from,
+                    balance: a.balance,
+                })
+            })
+            .collect()
+    }
+    /// sha256 of the parameters and the accounts in file order
+    pub fn hash(&self) -> Result<[u8; 32]> {
+        let mut h = Sha256::new();
+        h.input(b"loom genesis");
+        h.input(&self.network_id.to_le_bytes());
+        h.input(&self.fee.to_le_bytes());
+        h.input(&self.tick_rate.to_le_bytes());
+        h.input(&(self.accounts.len() as u64).to_le_bytes());
+        for a in self.accounts()? {
+            h.input(&a.from);
+            h.input(&a.balance.to_le_bytes());
+        }
+        let mut rv = [0u8; 32];
+        h.result(&mut rv);
+        Ok(rv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use genesis::{Account, Genesis, MAX_TICK_RATE};
+    use result::Error;
+    use std::fs::remove_file;
+
+    const KEY: &str = "UFC5KNCKS6KMC7VDIBVJ4R3IIJ0RLQL8VSVOAO4GQSMAV1QIPFP0====";
+
+    #[test]
+    fn genesis_file_test() {
+        let g = Genesis::from_file("testdata/genesis.json").expect("genesis");
+        assert_eq!(g.accounts.len(), 1);
+        let accounts = g.accounts().expect("accounts");
+        assert_eq!(accounts[0].balance, 1000000000);
+        g.to_file("TESTGENESIS").expect("write");
+        let n = Genesis::from_file("TESTGENESIS").expect("read back");
+        remove_file("TESTGENESIS").expect("remove");
+        assert_eq!(n, g);
+        assert_eq!(n.hash().unwrap(), g.hash().unwrap());
+    }
+    #[test]
+    fn genesis_tick_rate_test() {
+        let mut g = Genesis::from_file("testdata/genesis.json").expect("genesis");
+        g.tick_rate = MAX_TICK_RATE;
+        g.to_file("TESTGENESIS_TICK").expect("write");
+        assert!(Genesis::from_file("TESTGENESIS_TICK").is_ok());
+        g.tick_rate = MAX_TICK_RATE + 1;
+        g.to_file("TESTGENESIS_TICK").expect("write");
+        let rv = Genesis::from_file("TESTGENESIS_TICK");
+        remove_file("TESTGENESIS_TICK").expect("remove");
+        assert_matches!(rv, Err(Error::Corrupt));
+    }
+    #[test]
+    fn genesis_hash_test() {
+        let g = Genesis {
+            network_id: 1,
+            fee: 0,
+            tick_rate: 0,
+            accounts: vec![
+                Account {
+                    key: KEY.into(),
+                    balance: 5_000_000_000,
+                },
+            ],
+        };
+        let h = g.hash().expect("hash");
+        let mut o = g.clone();
+        o.network_id = 2;
+        assert_ne!(o.hash().unwrap(), h);
+        let mut o = g.clone();
+        o.accounts[0].balance += 1;
+        assert_ne!(o.hash().unwrap(), h);
+        let mut o = g.clone();
+        o.tick_rate = 1;
+        assert_ne!(o.hash().unwrap(), h);
+        let mut o = g.clone();
+        o.accounts[0].key = "00".into();
+        assert_matches!(o.hash(), Err(Error::Corrupt));
+    }
+}
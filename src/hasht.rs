@@ -6,7 +6,8 @@ pub trait Key: Eq {
     fn unused(&self) -> bool;
 }
 
-pub trait Val<T: Key>: Sized + Clone {
+/// `Default` must be an unused slot
+pub trait Val<T: Key>: Sized + Clone + Default {
     fn key(&self) -> &T;
 }
 
@@ -24,7 +25,7 @@ where
         let num_elems = tbl.len();
         let st = key.start();
         for i in 0..num_elems {
-            let pos = st.wrapping_add(i) % num_elems;
+            let pos = (st % num_elems + i) % num_elems;
             let k = tbl.get(pos).unwrap().key();
             if k.unused() || k == key {
                 return Ok(pos);
@@ -32,6 +33,34 @@ where
         }
         Err(Error::NoSpace)
     }
+    /// remove `key` with backward-shift deletion, entries further down the
+    /// probe sequence move up into the hole so `find` never needs tombstones
+    pub fn remove(tbl: &mut [V], key: &K) -> Result<Option<V>> {
+        let mut hole = Self::find(tbl, key)?;
+        if tbl[hole].key().unused() {
+            return Ok(None);
+        }
+        let rv = tbl[hole].clone();
+        let num_elems = tbl.len();
+        let mut pos = hole;
+        loop {
+            pos = (pos + 1) % num_elems;
+            if pos == hole || tbl[pos].key().unused() {
+                break;
+            }
+            //an entry can move back as long as that doesn't put it before its
+            //start position
+            let st = tbl[pos].key().start() % num_elems;
+            let from_start = (pos + num_elems - st) % num_elems;
+            let from_hole = (pos + num_elems - hole) % num_elems;
+            if from_start >= from_hole {
+                tbl[hole] = tbl[pos].clone();
+                hole = pos;
+            }
+        }
+        tbl[hole] = V::default();
+        Ok(Some(rv))
+    }
     pub fn migrate(src: &[V], dst: &mut [V]) -> Result<()> {
         for i in src {
             if i.key().unused() {
@@ -49,6 +78,8 @@ where
 mod test {
     use hasht;
     use result::Error;
+    use std::collections::HashSet;
+    use rand::{Rng, SeedableRng, StdRng};
     impl hasht::Key for usize {
         fn start(&self) -> usize {
             *self
@@ -120,5 +151,57 @@ mod test {
         m[mc] = 3;
         assert_eq!(UsizeT::find(&m, &3usize).unwrap(), mc);
     }
+    #[test]
+    fn hash_remove_test() {
+        //1, 9 and 17 all start at 1, 2 starts at 2 and is pushed to 3
+        let mut v = vec![0usize; 8];
+        for k in &[1usize, 9, 2, 17] {
+            let p = UsizeT::find(&v, k).expect("find");
+            v[p] = *k;
+        }
+        assert_eq!(v, [0, 1, 9, 2, 17, 0, 0, 0]);
+        assert_eq!(UsizeT::remove(&mut v, &9).unwrap(), Some(9));
+        assert_eq!(v, [0, 1, 2, 17, 0, 0, 0, 0]);
+        assert_eq!(UsizeT::remove(&mut v, &9).unwrap(), None);
+        assert_eq!(UsizeT::remove(&mut v, &1).unwrap(), Some(1));
+        assert_eq!(v, [0, 17, 2, 0, 0, 0, 0, 0]);
+        assert_eq!(UsizeT::find(&v, &2).unwrap(), 2);
+        assert_eq!(UsizeT::find(&v, &17).unwrap(), 1);
+        assert_matches!(UsizeT::remove(&mut vec![], &1), Err(Error::NoSpace));
+    }
+    #[test]
+    fn hash_remove_wrap_test() {
+        let mut v = vec![0usize; 4];
+        for k in &[3usize, 7, 11] {
+            let p = UsizeT::find(&v, k).expect("find");
+            v[p] = *k;
+        }
+        assert_eq!(v, [7, 11, 0, 3]);
+        assert_eq!(UsizeT::remove(&mut v, &3).unwrap(), Some(3));
+        assert_eq!(v, [11, 0, 0, 7]);
+        assert_eq!(UsizeT::remove(&mut v, &7).unwrap(), Some(7));
+        assert_eq!(v, [0, 0, 0, 11]);
+    }
+    #[test]
+    fn hash_remove_model_test() {
+        let mut v = vec![0usize; 64];
+        let mut model = HashSet::new();
+        let mut rng = StdRng::from_seed(&[1]);
+        for _ in 0..10000 {
+            let k = rng.gen_range(1, 96);
+            if model.len() < 48 && rng.gen() {
+                let p = UsizeT::find(&v, &k).expect("find");
+                v[p] = k;
+                model.insert(k);
+            } else {
+                let r = UsizeT::remove(&mut v, &k).expect("remove");
+                assert_eq!(r.is_some(), model.remove(&k));
+            }
+            for k in 1..96 {
+                let p = UsizeT::find(&v, &k).expect("find");
+                assert_eq!(v[p] == k, model.contains(&k));
+            }
+        }
+    }
 
 }
@@ -0,0 +1,193 @@
+//! bounded per account history of transfers
+//!
+//! entries are kept as `Kind::History` messages so they can be sent back as
+//! they are, see `data::History`
+
+use data;
+use std::cmp::min;
+use std::collections::{HashMap, VecDeque};
+
+/// entries kept per account unless configured otherwise
+pub const DEFAULT_MAX: usize = 64;
+
+/// most entries in one reply, they fit in one packet after the header
+pub const PAGE: usize = 16;
+
+/// entries kept over all accounts unless configured otherwise
+pub const DEFAULT_LIMIT: usize = 1 << 18;
+
+pub struct History {
+    max: usize,
+    limit: usize,
+    accounts: HashMap<[u8; 32], VecDeque<data::Message>>,
+    /// account and PoH count of every entry in the order they were pushed,
+    /// the ones already dropped by `max` or `forget` are skipped
+    order: VecDeque<([u8; 32], u64)>,
+}
+
+impl History {
+    /// keep the `max` newest entries of every account, 0 keeps nothing, and
+    /// the `DEFAULT_LIMIT` newest of all of them
+    pub fn new(max: usize) -> History {
+        History {
+            max: max,
+            limit: DEFAULT_LIMIT,
+            accounts: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+    /// keep at most `limit` entries over all accounts, the oldest go first
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        self.evict();
+    }
+    /// drop the entries of `key`, its account is gone
+    pub fn forget(&mut self, key: &[u8; 32]) {
+        self.accounts.remove(key);
+    }
+    pub fn set_max(&mut self, max: usize) {
+        self.max = max;
+        for v in self.accounts.values_mut() {
+            while v.len() > max {
+                v.pop_front();
+            }
+        }
+        self.accounts.retain(|_, v| !v.is_empty());
+    }
+    /// record the transfer `m` of `amount` to `to` on both accounts, `count`
+    /// is the PoH count it was recorded at
+    pub fn record(&mut self, m: &data::Message, to: [u8; 32], amount: u64, count: u64) {
+        if self.max == 0 {
+            return;
+        }
+        let mut e = data::Message::default();
+        e.pld.kind = data::Kind::History;
+        e.pld.fee = m.pld.fee;
+        e.pld.lvh_count = count;
+        e.sig = m.sig;
+        e.pld.from = m.pld.from;
+        e.pld.state = data::State::Withdrawn;
+        *e.pld.get_entry_mut() = data::History {
+            counterparty: to,
+            amount: amount,
+        };
+        self.push(e);
+        e.pld.from = to;
+        e.pld.state = data::State::Deposited;
+        e.pld.get_entry_mut().counterparty = m.pld.from;
+        self.push(e);
+    }
+    fn push(&mut self, e: data::Message) {
+        {
+            let v = self.accounts.entry(e.pld.from).or_default();
+            if v.len() == self.max {
+                v.pop_front();
+            }
+            v.push_back(e);
+        }
+        self.order.push_back((e.pld.from, e.pld.lvh_count));
+        self.evict();
+    }
+    /// drop the oldest entries until `order` is within `limit`, it holds
+    /// every entry still kept so they are too
+    fn evict(&mut self) {
+        while self.order.len() > self.limit {
+            let (key, count) = self.order.pop_front().unwrap();
+            let empty = match self.accounts.get_mut(&key) {
+                Some(v) => {
+                    if v.front().map(|e| e.pld.lvh_count) == Some(count) {
+                        v.pop_front();
+                    }
+                    v.is_empty()
+                }
+                None => false,
+            };
+            if empty {
+                self.accounts.remove(&key);
+            }
+        }
+    }
+    /// up to `num` entries of `key`, newest first, skipping the `start` newest
+    pub fn page(&self, key: &[u8; 32], start: usize, num: usize) -> Vec<data::Message> {
+        match self.accounts.get(key) {
+            Some(v) => v.iter().rev().skip(start).take(min(num, PAGE)).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+    /// number of entries kept for `key`
+    pub fn len(&self, key: &[u8; 32]) -> usize {
+        self.accounts.get(key).map(|v| v.len()).unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data;
+    use history::{History, PAGE};
+
+    fn tx(from: u8, amount: u64) -> data::Message {
+        let mut m = data::Message::default();
+        m.pld.kind = data::Kind::Transaction;
+        m.pld.from = [from; 32];
+        m.pld.fee = 1;
+        m.sig[0] = amount as u8;
+        m
+    }
+    #[test]
+    fn history_test() {
+        let mut h = History::new(4);
+        for i in 0..6 {
+            h.record(&tx(1, i), [2; 32], i, i + 10);
+        }
+        assert_eq!(h.len(&[1; 32]), 4);
+        assert_eq!(h.len(&[2; 32]), 4);
+        let p = h.page(&[1; 32], 1, 2);
+        assert_eq!(p.len(), 2);
+        assert_eq!(p[0].pld.get_entry().amount, 4);
+        assert_eq!(p[0].pld.lvh_count, 14);
+        assert_eq!(p[0].sig[0], 4);
+        assert_eq!(p[0].pld.state, data::State::Withdrawn);
+        assert_eq!(p[0].pld.get_entry().counterparty, [2; 32]);
+        assert_eq!(p[1].pld.get_entry().amount, 3);
+        let p = h.page(&[2; 32], 0, 8);
+        assert_eq!(p.len(), 4);
+        assert_eq!(p[0].pld.state, data::State::Deposited);
+        assert_eq!(p[0].pld.get_entry().counterparty, [1; 32]);
+        assert_eq!(p[3].pld.get_entry().amount, 2);
+        assert!(h.page(&[2; 32], 4, 8).is_empty());
+        assert!(h.page(&[3; 32], 0, 8).is_empty());
+        h.set_max(1);
+        assert_eq!(h.page(&[1; 32], 0, 8)[0].pld.get_entry().amount, 5);
+        h.set_max(0);
+        h.record(&tx(1, 9), [2; 32], 9, 19);
+        assert_eq!(h.len(&[1; 32]), 0);
+    }
+    #[test]
+    fn history_limit_test() {
+        let mut h = History::new(4);
+        h.set_limit(6);
+        //0 and 1 to 2, then 1 and 2 to 3, each transfer is two entries
+        h.record(&tx(1, 0), [2; 32], 0, 0);
+        h.record(&tx(1, 1), [2; 32], 1, 1);
+        h.record(&tx(2, 2), [3; 32], 2, 2);
+        h.record(&tx(2, 3), [3; 32], 3, 3);
+        assert_eq!(h.len(&[1; 32]), 1);
+        assert_eq!(h.len(&[2; 32]), 3);
+        assert_eq!(h.len(&[3; 32]), 2);
+        assert_eq!(h.page(&[1; 32], 0, 8)[0].pld.get_entry().amount, 1);
+        h.forget(&[2; 32]);
+        assert_eq!(h.len(&[2; 32]), 0);
+        h.set_limit(1);
+        assert_eq!(h.len(&[1; 32]), 0);
+        assert_eq!(h.len(&[3; 32]), 1);
+        assert_eq!(h.accounts.len(), 1);
+    }
+    #[test]
+    fn history_page_test() {
+        let mut h = History::new(PAGE * 2);
+        for i in 0..PAGE as u64 * 2 {
+            h.record(&tx(1, i), [2; 32], i, i);
+        }
+        assert_eq!(h.page(&[1; 32], 0, PAGE * 2).len(), PAGE);
+    }
+}
@@ -22,11 +22,18 @@ pub mod result;
 pub mod wallet;
 pub mod reader;
 pub mod state;
+pub mod table;
 pub mod aes;
 pub mod daemon;
 pub mod sender;
 pub mod client;
+pub mod proxy;
+pub mod poh;
+pub mod history;
+pub mod priority;
+pub mod genesis;
 
 #[cfg(test)]
 #[macro_use]
 extern crate matches;
+pub mod limit;
@@ -0,0 +1,122 @@
+//! per address rate limit for the free queries
+//!
+//! every address has a bucket of `burst` tokens that refills at `rate`
+//! tokens a second, a query takes one token and is dropped if there is none
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// free queries a second per address unless configured otherwise
+pub const DEFAULT_RATE: u64 = 100;
+
+/// buckets kept before the full ones are forgotten
+const MAX_BUCKETS: usize = 64 * 1024;
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: u64,
+    last: Instant,
+}
+
+pub struct Limiter {
+    rate: u64,
+    burst: u64,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl Limiter {
+    /// `rate` queries a second per address with bursts of up to `burst`,
+    /// a `rate` of 0 lets everything through
+    pub fn new(rate: u64, burst: u64) -> Limiter {
+        Limiter {
+            rate: rate,
+            burst: burst.max(1),
+            buckets: HashMap::new(),
+        }
+    }
+    /// take a token for `ip`, false if it is over its rate
+    pub fn allow(&mut self, ip: IpAddr, now: Instant) -> bool {
+        if self.rate == 0 {
+            return true;
+        }
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&ip) {
+            self.forget_full(now);
+        }
+        let burst = self.burst;
+        let b = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+        let refill = Self::refill(self.rate, b, now);
+        if refill > 0 {
+            b.tokens = min(burst, b.tokens.saturating_add(refill));
+            b.last = now;
+        }
+        if b.tokens == 0 {
+            return false;
+        }
+        b.tokens -= 1;
+        true
+    }
+    /// tokens earned since the bucket was last refilled
+    fn refill(rate: u64, b: &Bucket, now: Instant) -> u64 {
+        let dt = now.saturating_duration_since(b.last);
+        dt.as_secs()
+            .saturating_mul(rate)
+            .saturating_add(u64::from(dt.subsec_nanos()).saturating_mul(rate) / 1_000_000_000)
+    }
+    /// drop the buckets that would be full by now, they are the same as new
+    fn forget_full(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets
+            .retain(|_, b| b.tokens.saturating_add(Self::refill(rate, b, now)) < burst);
+    }
+    /// number of addresses being tracked
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use limit::Limiter;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn limit_test() {
+        let mut l = Limiter::new(10, 2);
+        let a: IpAddr = "127.0.0.1".parse().unwrap();
+        let b: IpAddr = "127.0.0.2".parse().unwrap();
+        let now = Instant::now();
+        assert!(l.allow(a, now));
+        assert!(l.allow(a, now));
+        assert!(!l.allow(a, now));
+        assert!(l.allow(b, now));
+        //one token every 100ms
+        assert!(!l.allow(a, now + Duration::from_millis(50)));
+        assert!(l.allow(a, now + Duration::from_millis(100)));
+        assert!(!l.allow(a, now + Duration::from_millis(150)));
+        //never more than the burst
+        let later = now + Duration::from_secs(60);
+        assert!(l.allow(a, later));
+        assert!(l.allow(a, later));
+        assert!(!l.allow(a, later));
+        assert_eq!(l.len(), 2);
+    }
+    #[test]
+    fn unlimited_test() {
+        let mut l = Limiter::new(0, 0);
+        let a: IpAddr = "127.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        for _ in 0..1000 {
+            assert!(l.allow(a, now));
+        }
+        assert!(l.is_empty());
+    }
+}
@@ -6,13 +6,20 @@ use std::cmp::min;
 use std::net::UdpSocket;
 use std::mem::transmute;
 use std::mem::size_of;
+use std::ptr::read_unaligned;
 use std::slice::from_raw_parts;
 use std::net::SocketAddr;
 use std::net::Ipv4Addr;
 use std::net::IpAddr;
-use data::{Message, MAX_PACKET};
+use std::io::ErrorKind;
+use std::os::unix::io::FromRawFd;
+use std::time::{Duration, Instant};
+use data::{CompactTx, Message, COMPACT_MAGIC, MAX_COMPACT, MAX_PACKET};
 use result::Result;
+use result::Error;
 use result::Error::IO;
+use nix::sys::socket;
+use nix::sys::socket::{sockopt, AddressFamily, InetAddr, SockAddr, SockFlag, SockType};
 
 pub fn bindall(port: u16) -> Result<UdpSocket> {
     let ipv4 = Ipv4Addr::new(0, 0, 0, 0);
@@ -21,6 +28,19 @@ pub fn bindall(port: u16) -> Result<UdpSocket> {
     return Ok(rv);
 }
 
+/// bind a udp socket with SO_REUSEPORT set so multiple sockets can share `addr`
+pub fn bind_reuseport(addr: SocketAddr) -> Result<UdpSocket> {
+    let family = match addr {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+    let fd = socket::socket(family, SockType::Datagram, SockFlag::SOCK_CLOEXEC, None)?;
+    let sock = unsafe { UdpSocket::from_raw_fd(fd) };
+    socket::setsockopt(fd, sockopt::ReusePort, &true)?;
+    socket::bind(fd, &SockAddr::new_inet(InetAddr::from_std(&addr)))?;
+    Ok(sock)
+}
+
 pub fn socket() -> Result<UdpSocket> {
     let ret = UdpSocket::bind("0.0.0.0:0")?;
     Ok(ret)
@@ -38,11 +58,12 @@ pub fn read_from(
     socket.set_nonblocking(false)?;
     while total < max {
         let p = &mut messages[total] as *mut Message;
-        if (max - total) * sz < MAX_PACKET {
+        //a packet of compact transfers takes more room once expanded
+        if (max - total) * sz < MAX_PACKET || max - total < MAX_COMPACT {
             return Ok(ix);
         }
         assert!(cfg!(target_endian = "little"));
-        let buf = unsafe { transmute(from_raw_parts(p as *mut u8, MAX_PACKET)) };
+        let buf: &mut [u8] = unsafe { transmute(from_raw_parts(p as *mut u8, MAX_PACKET)) };
         trace!("recv_from");
         match socket.recv_from(buf) {
             Err(_) if ix > 0 => {
@@ -55,9 +76,13 @@ pub fn read_from(
             }
             Ok((nrecv, from)) => {
                 trace!("got recv_from {:?}", nrecv);
-                total += nrecv / sz;
+                let num = match compact_len(&buf[..nrecv]) {
+                    Some(_) => expand(&buf[..nrecv], &mut messages[total..]),
+                    None => nrecv / sz,
+                };
+                total += num;
                 trace!("total recv_from {:?}", total);
-                *mdata.get_mut(ix).unwrap() = (nrecv / sz, from);
+                *mdata.get_mut(ix).unwrap() = (num, from);
                 ix += 1;
                 socket.set_nonblocking(true)?;
             }
@@ -116,6 +141,240 @@ pub fn send_to(
     Ok(())
 }
 
+/// send `txs` to `addr` in packets of up to `MAX_COMPACT`, `num` counts
+/// the ones sent like in `send_to`
+pub fn send_compact(
+    socket: &UdpSocket,
+    txs: &[CompactTx],
+    num: &mut usize,
+    addr: SocketAddr,
+) -> Result<()> {
+    let csz = size_of::<CompactTx>();
+    while *num < txs.len() {
+        let n = min(MAX_COMPACT, txs.len() - *num);
+        let p = &txs[*num] as *const CompactTx;
+        assert!(cfg!(target_endian = "little"));
+        let mut buf = COMPACT_MAGIC.to_le_bytes().to_vec();
+        buf.extend_from_slice(unsafe { from_raw_parts(p as *const u8, n * csz) });
+        socket.send_to(&buf, &addr)?;
+        *num += n;
+    }
+    Ok(())
+}
+
+/// number of compact transfers in `buf` if it is a packet of them
+fn compact_len(buf: &[u8]) -> Option<usize> {
+    let (hsz, csz) = (size_of::<u32>(), size_of::<CompactTx>());
+    if buf.len() <= hsz || buf.len() % size_of::<Message>() == 0 || (buf.len() - hsz) % csz != 0 {
+        return None;
+    }
+    let mut magic = [0u8; 4];
+    magic.copy_from_slice(&buf[..hsz]);
+    if u32::from_le_bytes(magic) != COMPACT_MAGIC {
+        return None;
+    }
+    Some((buf.len() - hsz) / csz)
+}
+
+/// turn a packet of compact transfers into messages at the front of
+/// `msgs`, `buf` may point into `msgs` so it is copied first
+fn expand(buf: &[u8], msgs: &mut [Message]) -> usize {
+    let csz = size_of::<CompactTx>();
+    let raw = buf[size_of::<u32>()..].to_vec();
+    let num = raw.len() / csz;
+    for (i, m) in msgs[..num].iter_mut().enumerate() {
+        let p = raw[i * csz..].as_ptr() as *const CompactTx;
+        *m = unsafe { read_unaligned(p) }.to_message();
+    }
+    num
+}
+
+/// timeout and retry settings for `request`
+#[derive(Clone, Copy, Debug)]
+pub struct Retry {
+    /// how long to wait for the first reply
+    pub timeout: Duration,
+    /// how many times to resend after the first attempt
+    pub retries: usize,
+    /// each retry waits `backoff` times longer than the previous one
+    pub backoff: u32,
+}
+
+/// longest a single attempt waits no matter the backoff
+pub const MAX_TIMEOUT: Duration = Duration::from_secs(60);
+
+impl Default for Retry {
+    fn default() -> Retry {
+        Retry {
+            timeout: Duration::from_millis(250),
+            retries: 4,
+            backoff: 2,
+        }
+    }
+}
+
+impl Retry {
+    /// the timeout of the attempt after one that waited `timeout`
+    fn next(&self, timeout: Duration) -> Duration {
+        timeout
+            .checked_mul(self.backoff)
+            .map_or(MAX_TIMEOUT, |t| min(t, MAX_TIMEOUT))
+    }
+}
+
+/// send `msg` to `addr` and wait for a reply carrying the same signature,
+/// resending with backoff until `retry` is exhausted
+pub fn request(socket: &UdpSocket, msg: &Message, addr: SocketAddr, retry: &Retry) -> Result<Message> {
+    let rv = request_all(socket, msg, addr, retry)?;
+    Ok(rv[0])
+}
+
+/// like `request`, but also returns the messages that follow the reply in
+/// its packet
+pub fn request_all(
+    socket: &UdpSocket,
+    msg: &Message,
+    addr: SocketAddr,
+    retry: &Retry,
+) -> Result<Vec<Message>> {
+    request_with(socket, msg, addr, retry, || {
+        let mut num = 0;
+        while num < 1 {
+            send_to(socket, &[*msg], &mut num, addr)?;
+        }
+        Ok(())
+    })
+}
+
+/// `request_all` for a compact transfer, the reply is a full `Message`
+pub fn request_compact(
+    socket: &UdpSocket,
+    tx: &CompactTx,
+    addr: SocketAddr,
+    retry: &Retry,
+) -> Result<Vec<Message>> {
+    request_with(socket, &tx.to_message(), addr, retry, || {
+        let mut num = 0;
+        while num < 1 {
+            send_compact(socket, &[*tx], &mut num, addr)?;
+        }
+        Ok(())
+    })
+}
+
+/// call `send` and wait for the reply to `msg`, resending with backoff
+fn request_with<F>(
+    socket: &UdpSocket,
+    msg: &Message,
+    addr: SocketAddr,
+    retry: &Retry,
+    send: F,
+) -> Result<Vec<Message>>
+where
+    F: Fn() -> Result<()>,
+{
+    let mut timeout = retry.timeout;
+    for attempt in 0..retry.retries + 1 {
+        send()?;
+        trace!("request attempt {:?} timeout {:?}", attempt, timeout);
+        let deadline = Instant::now() + timeout;
+        if let Some(rv) = recv_reply(socket, msg, addr, deadline)? {
+            return Ok(rv);
+        }
+        timeout = retry.next(timeout);
+    }
+    Err(Error::Timeout)
+}
+
+/// like `request` for several messages at once, the replies come back in
+/// the order of `msgs`, only the unanswered ones are resent
+pub fn request_many(
+    socket: &UdpSocket,
+    msgs: &[Message],
+    addr: SocketAddr,
+    retry: &Retry,
+) -> Result<Vec<Message>> {
+    let mut replies: Vec<Option<Message>> = vec![None; msgs.len()];
+    let mut timeout = retry.timeout;
+    for attempt in 0..retry.retries + 1 {
+        let pending: Vec<Message> = msgs.iter()
+            .zip(replies.iter())
+            .filter(|&(_, r)| r.is_none())
+            .map(|(m, _)| *m)
+            .collect();
+        if pending.is_empty() {
+            break;
+        }
+        let mut num = 0;
+        while num < pending.len() {
+            send_to(socket, &pending, &mut num, addr)?;
+        }
+        trace!("request attempt {:?} timeout {:?}", attempt, timeout);
+        let deadline = Instant::now() + timeout;
+        while replies.iter().any(|r| r.is_none()) {
+            let got = match recv_packet(socket, addr, deadline)? {
+                Some(got) => got,
+                None => break,
+            };
+            for g in got {
+                let i = msgs.iter()
+                    .zip(replies.iter())
+                    .position(|(m, r)| r.is_none() && m.sig[..] == g.sig[..]);
+                if let Some(i) = i {
+                    replies[i] = Some(g);
+                }
+            }
+        }
+        timeout = retry.next(timeout);
+    }
+    replies.into_iter().map(|r| r.ok_or(Error::Timeout)).collect()
+}
+
+fn recv_reply(
+    socket: &UdpSocket,
+    msg: &Message,
+    addr: SocketAddr,
+    deadline: Instant,
+) -> Result<Option<Vec<Message>>> {
+    while let Some(got) = recv_packet(socket, addr, deadline)? {
+        if let Some(i) = got.iter().position(|m| m.sig[..] == msg.sig[..]) {
+            return Ok(Some(got[i..].to_vec()));
+        }
+    }
+    Ok(None)
+}
+
+/// the messages of the next packet from `addr`, `None` once `deadline` passes
+fn recv_packet(socket: &UdpSocket, addr: SocketAddr, deadline: Instant) -> Result<Option<Vec<Message>>> {
+    let sz = size_of::<Message>();
+    let mut msgs = vec![Message::default(); MAX_PACKET / sz + 1];
+    socket.set_nonblocking(false)?;
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        socket.set_read_timeout(Some(deadline - now))?;
+        let p = &mut msgs[0] as *mut Message;
+        assert!(cfg!(target_endian = "little"));
+        let buf = unsafe { transmute(from_raw_parts(p as *mut u8, MAX_PACKET)) };
+        let (nrecv, from) = match socket.recv_from(buf) {
+            Ok(r) => r,
+            Err(ref e)
+                if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut =>
+            {
+                return Ok(None)
+            }
+            Err(e) => return Err(IO(e)),
+        };
+        if from != addr {
+            continue;
+        }
+        msgs.truncate(nrecv / sz);
+        return Ok(Some(msgs));
+    }
+}
+
 #[test]
 fn read_write_test() {
     let sz = size_of::<Message>();
@@ -132,3 +391,181 @@ fn read_write_test() {
     assert!(num == max);
     assert!(num > 0);
 }
+
+#[test]
+fn compact_test() {
+    let srv = bindall(12351).expect("server");
+    let cli = socket().expect("socket");
+    let addr = "127.0.0.1:12351".parse().expect("parse");
+    let txs: Vec<CompactTx> = (0..MAX_COMPACT + 9)
+        .map(|i| {
+            let mut tx = CompactTx::default();
+            tx.compact.amount = i as u64;
+            tx.sig[0] = i as u8;
+            tx
+        })
+        .collect();
+    let mut num = 0;
+    send_compact(&cli, &txs, &mut num, addr).expect("send");
+    assert_eq!(num, txs.len());
+    let mut num = 0;
+    send_to(&cli, &[Message::default()], &mut num, addr).expect("send");
+    let mut m = vec![Message::default(); 1024];
+    let mut d = vec![(0, addr); 1024];
+    let mut got = Vec::new();
+    let mut total = 0;
+    while got.len() < 3 {
+        let n = read_from(&srv, &mut m[total..], &mut d[got.len()..]).expect("read");
+        total += d[got.len()..got.len() + n].iter().map(|d| d.0).sum::<usize>();
+        got.extend(d[got.len()..got.len() + n].iter().map(|d| d.0));
+    }
+    assert_eq!(got, vec![MAX_COMPACT, 9, 1]);
+    for (i, m) in m[..txs.len()].iter().enumerate() {
+        assert_eq!(m.pld.get_compact().amount, i as u64);
+        assert_eq!(m.sig[0], i as u8);
+    }
+    assert_eq!(m[txs.len()].pld.kind, ::data::Kind::Invalid);
+}
+
+#[test]
+fn request_timeout_test() {
+    let srv = bindall(12347).expect("server");
+    let cli = socket().expect("socket");
+    let addr = "127.0.0.1:12347".parse().expect("parse");
+    let retry = Retry {
+        timeout: Duration::from_millis(10),
+        retries: 2,
+        backoff: 2,
+    };
+    let msg = Message::default();
+    let start = Instant::now();
+    match request(&cli, &msg, addr, &retry) {
+        Err(Error::Timeout) => (),
+        _ => panic!("expected a timeout"),
+    }
+    assert!(start.elapsed() >= Duration::from_millis(70));
+    let mut m = [Message::default(); 64];
+    let mut d = [(0, addr); 64];
+    let n = read_from(&srv, &mut m, &mut d).expect("read");
+    assert_eq!(n, 3);
+}
+
+#[test]
+fn request_retry_test() {
+    use proxy::{Policy, Proxy};
+    use std::thread::spawn;
+    let srv = bindall(12348).expect("server");
+    let addr = "127.0.0.1:12348".parse().expect("parse");
+    let mut policy = Policy::default();
+    policy.seed = 3;
+    policy.drop = 0.5;
+    let p = Proxy::new(addr, policy).expect("proxy");
+    srv.set_read_timeout(Some(Duration::from_millis(500)))
+        .expect("timer");
+    let t = spawn(move || {
+        let mut total = 0;
+        let mut m = [Message::default(); 64];
+        let mut d = [(0, addr); 64];
+        while let Ok(n) = read_from(&srv, &mut m, &mut d) {
+            total += n;
+            for &(z, a) in d[..n].iter() {
+                let mut num = 0;
+                send_to(&srv, &m[..z], &mut num, a).expect("reply");
+            }
+        }
+        total
+    });
+    let cli = socket().expect("socket");
+    let mut msg = Message::default();
+    msg.sig[0] = 1;
+    let other = Message::default();
+    let mut num = 0;
+    send_to(&cli, &[other], &mut num, p.addr()).expect("stray");
+    let retry = Retry {
+        timeout: Duration::from_millis(50),
+        retries: 20,
+        backoff: 1,
+    };
+    let rv = request(&cli, &msg, p.addr(), &retry).expect("request");
+    assert_eq!(rv.sig[0], 1);
+    assert!(t.join().unwrap() > 0);
+}
+
+#[test]
+fn bind_reuseport_test() {
+    let addr = "127.0.0.1:12346".parse().expect("parse");
+    let a = bind_reuseport(addr).expect("first socket");
+    let b = bind_reuseport(addr).expect("second socket");
+    assert_eq!(a.local_addr().unwrap(), b.local_addr().unwrap());
+    assert!(UdpSocket::bind(&addr).is_err());
+}
+
+#[test]
+fn request_all_test() {
+    use std::thread::spawn;
+    let srv = bindall(12349).expect("server");
+    let addr = "127.0.0.1:12349".parse().expect("parse");
+    let t = spawn(move || {
+        let mut m = [Message::default(); 64];
+        let mut d = [(0, addr); 64];
+        read_from(&srv, &mut m, &mut d).expect("read");
+        let mut reply = [Message::default(); 4];
+        reply[1] = m[0];
+        reply[2].sig[0] = 2;
+        reply[3].sig[0] = 3;
+        let mut num = 0;
+        send_to(&srv, &reply, &mut num, d[0].1).expect("reply");
+    });
+    let cli = socket().expect("socket");
+    let mut msg = Message::default();
+    msg.sig[0] = 1;
+    let rv = request_all(&cli, &msg, addr, &Retry::default()).expect("request");
+    let sigs: Vec<u8> = rv.iter().map(|m| m.sig[0]).collect();
+    assert_eq!(sigs, vec![1, 2, 3]);
+    t.join().unwrap();
+}
+
+#[test]
+fn retry_next_test() {
+    let mut r = Retry::default();
+    assert_eq!(r.next(Duration::from_millis(250)), Duration::from_millis(500));
+    assert_eq!(r.next(Duration::from_secs(59)), MAX_TIMEOUT);
+    r.backoff = u32::max_value();
+    assert_eq!(r.next(Duration::new(u64::max_value(), 0)), MAX_TIMEOUT);
+}
+
+#[test]
+fn request_many_test() {
+    use std::thread::spawn;
+    let srv = bindall(12350).expect("server");
+    let addr = "127.0.0.1:12350".parse().expect("parse");
+    let t = spawn(move || {
+        let mut m = [Message::default(); 64];
+        let mut d = [(0, addr); 64];
+        //answer all but the first, in reverse
+        let n = read_from(&srv, &mut m, &mut d).expect("read");
+        assert_eq!((n, d[0].0), (1, 3));
+        let reply = [m[2], m[1]];
+        let mut num = 0;
+        send_to(&srv, &reply, &mut num, d[0].1).expect("reply");
+        //only the first is resent
+        read_from(&srv, &mut m, &mut d).expect("read");
+        assert_eq!(d[0].0, 1);
+        let mut num = 0;
+        send_to(&srv, &m[..1], &mut num, d[0].1).expect("reply");
+    });
+    let cli = socket().expect("socket");
+    let mut msgs = [Message::default(); 3];
+    for (i, m) in msgs.iter_mut().enumerate() {
+        m.sig[0] = i as u8 + 1;
+    }
+    let retry = Retry {
+        timeout: Duration::from_millis(100),
+        retries: 4,
+        backoff: 1,
+    };
+    let rv = request_many(&cli, &msgs, addr, &retry).expect("request");
+    let sigs: Vec<u8> = rv.iter().map(|m| m.sig[0]).collect();
+    assert_eq!(sigs, vec![1, 2, 3]);
+    t.join().unwrap();
+}
@@ -1,23 +1,54 @@
 //! see test for usage
 //! small actor library for named channels inspired by erlang OTP
+//!
+//! listeners are supervised, an error or a panic in a handler is reported to
+//! `Port::Supervisor` and handled according to the port's `Restart` policy,
+//! reports pile up to `MAX_REPORTS` if nothing listens on the supervisor
+//!
+//! every port counts what goes in and out and how long its handlers take,
+//! see `OTP::metrics`
+//!
+//! `OTP::deterministic` runs every source and listener on the caller's thread
+//! in a seeded order, one `step` at a time, for tests
+//!
+//! listeners block on their queue, `stop` and `shutdown` wake them up by
+//! posting a notice to every queue, sources are expected to block in `func`
 
 use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread::{spawn, JoinHandle};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::collections::VecDeque;
 use std::net::SocketAddr;
+use rand::{Rng, SeedableRng, StdRng};
 use data;
 use result::Result;
 use result::Error;
 
+/// handle to a port, the first five are always registered, everything else
+/// is added at runtime with `OTP::register` and comes back as `Port::Id`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Port {
     Main,
     Reader,
     State,
     Recycle,
     Sender,
+    Supervisor,
+    Id(usize),
 }
 
+const BUILTIN: [&str; 6] = [
+    "main",
+    "reader",
+    "state",
+    "recycle",
+    "sender",
+    "supervisor",
+];
+
 impl Port {
     fn to_usize(self) -> usize {
         match self {
@@ -26,6 +57,19 @@ impl Port {
             Port::State => 2,
             Port::Recycle => 3,
             Port::Sender => 4,
+            Port::Supervisor => 5,
+            Port::Id(i) => i,
+        }
+    }
+    fn from_usize(i: usize) -> Port {
+        match i {
+            0 => Port::Main,
+            1 => Port::Reader,
+            2 => Port::State,
+            3 => Port::Recycle,
+            4 => Port::Sender,
+            5 => Port::Supervisor,
+            i => Port::Id(i),
         }
     }
 }
@@ -35,72 +79,389 @@ pub enum Data {
     Signal,
     SharedMessages(data::SharedMessages),
     SendMessage(data::Message, SocketAddr),
+    /// messages that go out together in one packet
+    SendMessages(Vec<data::Message>, SocketAddr),
+    /// like `SendMessages`, the last one is signed by the node's identity
+    /// on the way out, so the signing doesn't hold up `State`
+    SendSigned(Vec<data::Message>, SocketAddr),
+    Report(Report),
+}
+
+/// what actually goes through a port's channel
+enum Mail {
+    Data(Data),
+    /// check the exit and stop flags
+    Wake,
+}
+
+/// what happens when a listener's handler fails
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Restart {
+    /// drop the failed message and keep going, escalate if there are more
+    /// than `max` failures within `period`. Only the listener's loop starts
+    /// over, the handler is the same closure with the same captured state,
+    /// so a mutex it panicked with stays poisoned
+    Restart { max: usize, period: Duration },
+    /// stop the failed listener, `shutdown` returns its error
+    Escalate,
+    /// stop every thread, `join` returns
+    StopAll,
+}
+
+impl Default for Restart {
+    fn default() -> Restart {
+        Restart::Restart {
+            max: 100,
+            period: Duration::new(1, 0),
+        }
+    }
+}
+
+/// reports waiting on `Port::Supervisor` past this are dropped
+pub const MAX_REPORTS: usize = 1024;
+
+/// sent to `Port::Supervisor` every time a handler fails
+#[derive(Clone, Debug)]
+pub struct Report {
+    pub port: Port,
+    pub error: String,
+    /// total number of restarts of this port so far
+    pub restarts: usize,
+    /// what the supervisor did about it
+    pub action: Restart,
+}
+
+struct Supervision {
+    policy: Mutex<Restart>,
+    restarts: AtomicUsize,
+    recent: Mutex<VecDeque<Instant>>,
+}
+
+impl Supervision {
+    fn new() -> Supervision {
+        Supervision {
+            policy: Mutex::new(Restart::default()),
+            restarts: AtomicUsize::new(0),
+            recent: Mutex::new(VecDeque::new()),
+        }
+    }
+    /// decide what to do about a failure, `Restart` is only returned while
+    /// the failure rate is within the policy's limit
+    fn failed(&self) -> Restart {
+        let policy = *self.policy.lock().unwrap();
+        match policy {
+            Restart::Restart { max, period } => {
+                let now = Instant::now();
+                let mut recent = self.recent.lock().unwrap();
+                while recent
+                    .front()
+                    .map(|t| now.duration_since(*t) > period)
+                    .unwrap_or(false)
+                {
+                    recent.pop_front();
+                }
+                if recent.len() >= max {
+                    return Restart::Escalate;
+                }
+                recent.push_back(now);
+                self.restarts.fetch_add(1, Ordering::Relaxed);
+                policy
+            }
+            p => p,
+        }
+    }
+}
+
+/// number of buckets in `Metrics::handler`
+pub const BUCKETS: usize = 32;
+
+#[derive(Default)]
+struct Counters {
+    sent: AtomicUsize,
+    handled: AtomicUsize,
+    handler: [AtomicUsize; BUCKETS],
+}
+
+impl Counters {
+    fn record(&self, d: Duration) {
+        let us = d.as_secs() * 1_000_000 + u64::from(d.subsec_micros());
+        let ix = 64 - us.leading_zeros() as usize;
+        self.handler[ix.min(BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.handled.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// snapshot of a port's counters
+#[derive(Clone, Debug, PartialEq)]
+pub struct Metrics {
+    pub port: Port,
+    pub name: String,
+    /// messages sent to the port
+    pub sent: usize,
+    /// messages its listeners finished handling
+    pub handled: usize,
+    /// messages waiting in the queue or being handled
+    pub depth: usize,
+    /// log2 histogram of handler time, `handler[i]` counts calls that took
+    /// less than 2^i microseconds and at least 2^(i-1)
+    pub handler: Vec<usize>,
+}
+
+impl Metrics {
+    /// upper bound of the handler time of the `q` quantile, 0.0 to 1.0
+    pub fn quantile(&self, q: f64) -> Duration {
+        let total: usize = self.handler.iter().sum();
+        let want = (total as f64 * q).ceil() as usize;
+        let mut seen = 0;
+        for (i, n) in self.handler.iter().enumerate() {
+            seen += n;
+            if seen >= want.max(1) {
+                return Duration::from_micros(1u64 << i);
+            }
+        }
+        Duration::new(0, 0)
+    }
+}
+
+/// how messages sent to a port are spread across its workers
+#[derive(Clone, Copy)]
+pub enum Dispatch {
+    RoundRobin,
+    /// messages with the same key always go to the same worker
+    Key(fn(&Data) -> usize),
+}
+
+struct Route {
+    senders: Vec<Sender<Mail>>,
+    dispatch: Dispatch,
+    next: AtomicUsize,
+    counters: Arc<Counters>,
+}
+
+impl Route {
+    fn pick(&self, m: &Data) -> &Sender<Mail> {
+        let ix = match self.dispatch {
+            _ if self.senders.len() == 1 => 0,
+            Dispatch::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed),
+            Dispatch::Key(f) => f(m),
+        };
+        &self.senders[ix % self.senders.len()]
+    }
+}
+
+#[derive(Default)]
+struct Table {
+    names: Vec<String>,
+    routes: Vec<Route>,
+}
+
+/// shared registry of port names and channels, ports registered after a
+/// thread started are visible to it
+#[derive(Clone, Default)]
+pub struct Ports {
+    table: Arc<RwLock<Table>>,
+}
+
+impl Ports {
+    /// resolve a port registered under `name`
+    pub fn lookup(&self, name: &str) -> Result<Port> {
+        let t = self.table.read().unwrap();
+        let pz = t.names.iter().position(|n| n == name);
+        pz.map(Port::from_usize).ok_or(Error::OTPError)
+    }
+    pub fn name(&self, port: Port) -> Option<String> {
+        let t = self.table.read().unwrap();
+        t.names.get(port.to_usize()).cloned()
+    }
+    fn add(&self, name: &str, s: Sender<Mail>) -> Result<Port> {
+        let mut t = self.table.write().unwrap();
+        if t.names.iter().any(|n| n == name) {
+            return Err(Error::OTPError);
+        }
+        t.names.push(name.to_string());
+        t.routes.push(Route {
+            senders: vec![s],
+            dispatch: Dispatch::RoundRobin,
+            next: AtomicUsize::new(0),
+            counters: Arc::new(Counters::default()),
+        });
+        Ok(Port::from_usize(t.routes.len() - 1))
+    }
+    fn route(&self, port: Port, senders: Vec<Sender<Mail>>, dispatch: Dispatch) {
+        let mut t = self.table.write().unwrap();
+        let r = &mut t.routes[port.to_usize()];
+        r.senders.extend(senders);
+        r.dispatch = dispatch;
+    }
+    /// post a wake up notice to every queue of `port`
+    fn wake(&self, port: Port) {
+        let t = self.table.read().unwrap();
+        if let Some(r) = t.routes.get(port.to_usize()) {
+            for s in &r.senders {
+                let _ = s.send(Mail::Wake);
+            }
+        }
+    }
+    /// snapshot the counters of every port
+    pub fn metrics(&self) -> Vec<Metrics> {
+        let t = self.table.read().unwrap();
+        t.names
+            .iter()
+            .zip(t.routes.iter())
+            .enumerate()
+            .map(|(i, (n, r))| {
+                let c = &r.counters;
+                let sent = c.sent.load(Ordering::Relaxed);
+                let handled = c.handled.load(Ordering::Relaxed);
+                Metrics {
+                    port: Port::from_usize(i),
+                    name: n.clone(),
+                    sent: sent,
+                    handled: handled,
+                    depth: sent.saturating_sub(handled),
+                    handler: c.handler
+                        .iter()
+                        .map(|h| h.load(Ordering::Relaxed))
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+    /// messages sent to `port` and not handled yet
+    fn depth(&self, port: Port) -> usize {
+        let c = self.counters(port);
+        let sent = c.sent.load(Ordering::Relaxed);
+        sent.saturating_sub(c.handled.load(Ordering::Relaxed))
+    }
+    fn counters(&self, port: Port) -> Arc<Counters> {
+        self.table.read().unwrap().routes[port.to_usize()]
+            .counters
+            .clone()
+    }
+    fn len(&self) -> usize {
+        self.table.read().unwrap().routes.len()
+    }
 }
 
 struct Locked {
-    ports: Vec<Sender<Data>>,
-    readers: Vec<Arc<Mutex<Receiver<Data>>>>,
-    threads: Vec<Arc<Option<JoinHandle<Result<()>>>>>,
+    ports: Ports,
+    supervision: Vec<Arc<Supervision>>,
+    stops: Vec<Arc<AtomicBool>>,
+    readers: Vec<Arc<Mutex<Receiver<Mail>>>>,
+    threads: Vec<Vec<JoinHandle<Result<()>>>>,
+    listening: Vec<bool>,
+}
+
+type SourceFn = Box<dyn Fn(&Ports) -> Result<()> + Send>;
+type ListenFn = Box<dyn Fn(&Ports, Data) -> Result<()> + Send>;
+
+enum Task {
+    Source(SourceFn),
+    /// the message is taken off the queue before the task is picked
+    Listener(Arc<Mutex<Receiver<Mail>>>, ListenFn, Option<Data>),
+}
+
+struct Sched {
+    rng: StdRng,
+    tasks: Vec<(Port, Task)>,
 }
 
 pub struct OTP {
     lock: Arc<RwLock<Locked>>,
-    exit: Arc<Mutex<bool>>,
+    exit: Arc<AtomicBool>,
+    sched: Option<Mutex<Sched>>,
 }
 
-pub type Ports = Vec<Sender<Data>>;
-
 impl OTP {
     pub fn new() -> OTP {
-        let (s1, r1) = channel();
-        let (s2, r2) = channel();
-        let (s3, r3) = channel();
-        let (s4, r4) = channel();
-        let (s5, r5) = channel();
+        Self::with_sched(None)
+    }
+    /// single threaded runtime, sources and listeners only run when `step`,
+    /// `wait` or `stop` is called and they run in an order picked by `seed`
+    pub fn deterministic(seed: usize) -> OTP {
+        Self::with_sched(Some(Mutex::new(Sched {
+            rng: StdRng::from_seed(&[seed]),
+            tasks: Vec::new(),
+        })))
+    }
+    fn with_sched(sched: Option<Mutex<Sched>>) -> OTP {
         let locked = Locked {
-            ports: [s1, s2, s3, s4, s5].to_vec(),
-            readers: [
-                Arc::new(Mutex::new(r1)),
-                Arc::new(Mutex::new(r2)),
-                Arc::new(Mutex::new(r3)),
-                Arc::new(Mutex::new(r4)),
-                Arc::new(Mutex::new(r5)),
-            ].to_vec(),
-            threads: [
-                Arc::new(None),
-                Arc::new(None),
-                Arc::new(None),
-                Arc::new(None),
-                Arc::new(None),
-            ].to_vec(),
+            ports: Ports::default(),
+            supervision: Vec::new(),
+            stops: Vec::new(),
+            readers: Vec::new(),
+            threads: Vec::new(),
+            listening: Vec::new(),
         };
-        let exit = Arc::new(Mutex::new(false));
-        OTP {
+        let exit = Arc::new(AtomicBool::new(false));
+        let o = OTP {
             lock: Arc::new(RwLock::new(locked)),
             exit: exit,
+            sched: sched,
+        };
+        for name in BUILTIN.iter() {
+            o.register(name).expect("builtin port");
         }
+        o
+    }
+    /// add a new port named `name`, fails if the name is taken
+    pub fn register(&self, name: &str) -> Result<Port> {
+        let mut w = self.lock.write().unwrap();
+        let (s, r) = channel();
+        let port = w.ports.add(name, s)?;
+        w.supervision.push(Arc::new(Supervision::new()));
+        w.stops.push(Arc::new(AtomicBool::new(false)));
+        w.readers.push(Arc::new(Mutex::new(r)));
+        w.threads.push(Vec::new());
+        w.listening.push(false);
+        Ok(port)
+    }
+    /// resolve a port registered under `name`
+    pub fn port(&self, name: &str) -> Result<Port> {
+        self.lock.read().unwrap().ports.lookup(name)
     }
+    /// set the restart policy for the listeners on `port`
+    pub fn supervise(&self, port: Port, policy: Restart) -> Result<()> {
+        let r = self.lock.read().unwrap();
+        let sv = r.supervision.get(port.to_usize()).ok_or(Error::OTPError)?;
+        *sv.policy.lock().unwrap() = policy;
+        Ok(())
+    }
+    /// number of times the listeners on `port` were restarted
+    pub fn restarts(&self, port: Port) -> Result<usize> {
+        let r = self.lock.read().unwrap();
+        let sv = r.supervision.get(port.to_usize()).ok_or(Error::OTPError)?;
+        Ok(sv.restarts.load(Ordering::Relaxed))
+    }
+    /// spawn a thread that calls `func` in a loop until shutdown, a port can
+    /// have any number of sources but not if it already has a listener
     pub fn source<F>(&self, port: Port, func: F) -> Result<()>
     where
         F: Send + 'static + Fn(&Ports) -> Result<()>,
     {
         let mut w = self.lock.write().unwrap();
         let pz = port.to_usize();
-        if w.threads[pz].is_some() {
+        if *w.listening.get(pz).ok_or(Error::OTPError)? {
             return Err(Error::OTPError);
         }
+        if let Some(ref sched) = self.sched {
+            let task = Task::Source(Box::new(func));
+            sched.lock().unwrap().tasks.push((port, task));
+            return Ok(());
+        }
         let c_ports = w.ports.clone();
         let c_exit = self.exit.clone();
+        let c_stop = w.stops[pz].clone();
         let j = spawn(move || loop {
             match func(&c_ports) {
                 Ok(()) => (),
                 e => return e,
             }
-            if *c_exit.lock().unwrap() == true {
+            if c_exit.load(Ordering::Relaxed) || c_stop.load(Ordering::Relaxed) {
                 return Ok(());
             }
         });
-        w.threads[pz] = Arc::new(Some(j));
+        w.threads[pz].push(j);
         return Ok(());
     }
     pub fn listen<F>(&mut self, port: Port, func: F) -> Result<()>
@@ -109,61 +470,337 @@ impl OTP {
     {
         let mut w = self.lock.write().unwrap();
         let pz = port.to_usize();
-        if w.threads[pz].is_some() {
+        if self.running(&w, pz)? {
             return Err(Error::OTPError);
         }
         let recv_lock = w.readers[pz].clone();
+        self.spawn_listener(&mut w, port, recv_lock, func);
+        w.listening[pz] = true;
+        return Ok(());
+    }
+    /// attach `num` listener threads to `port`, each with its own queue, and
+    /// spread messages sent to the port across them according to `dispatch`
+    pub fn workers<F>(&mut self, port: Port, num: usize, dispatch: Dispatch, func: F) -> Result<()>
+    where
+        F: Send + Sync + 'static + Fn(&Ports, Data) -> Result<()>,
+    {
+        let mut w = self.lock.write().unwrap();
+        let pz = port.to_usize();
+        if num == 0 || self.running(&w, pz)? {
+            return Err(Error::OTPError);
+        }
+        let func = Arc::new(func);
+        let mut receivers = vec![w.readers[pz].clone()];
+        let mut senders = Vec::new();
+        for _ in 1..num {
+            let (s, r) = channel();
+            senders.push(s);
+            receivers.push(Arc::new(Mutex::new(r)));
+        }
+        for recv_lock in receivers {
+            let c_func = func.clone();
+            self.spawn_listener(&mut w, port, recv_lock, move |p, d| c_func(p, d));
+        }
+        w.ports.route(port, senders, dispatch);
+        w.listening[pz] = true;
+        return Ok(());
+    }
+    /// true if `port` already has a source or a listener
+    fn running(&self, w: &Locked, pz: usize) -> Result<bool> {
+        let threads = !w.threads.get(pz).ok_or(Error::OTPError)?.is_empty();
+        let tasks = match self.sched {
+            Some(ref s) => s.lock().unwrap().tasks.iter().any(|t| t.0.to_usize() == pz),
+            None => false,
+        };
+        Ok(threads || tasks)
+    }
+    fn spawn_listener<F>(
+        &self,
+        w: &mut Locked,
+        port: Port,
+        recv_lock: Arc<Mutex<Receiver<Mail>>>,
+        func: F,
+    ) where
+        F: Send + 'static + Fn(&Ports, Data) -> Result<()>,
+    {
+        if let Some(ref sched) = self.sched {
+            let task = Task::Listener(recv_lock, Box::new(func), None);
+            sched.lock().unwrap().tasks.push((port, task));
+            return;
+        }
         let c_ports = w.ports.clone();
+        let c_sv = w.supervision[port.to_usize()].clone();
         let c_exit = self.exit.clone();
-        let j: JoinHandle<Result<()>> = spawn(move || loop {
+        let c_stop = w.stops[port.to_usize()].clone();
+        let c_counters = w.ports.counters(port);
+        let j = spawn(move || loop {
             let recv = recv_lock.lock().unwrap();
-            let timer = Duration::new(0, 500000);
-            match recv.recv_timeout(timer) {
-                Ok(val) => func(&c_ports, val).expect("otp listen"),
-                _ => (),
+            match recv.recv() {
+                //the notice is queued behind everything sent before the stop
+                Ok(Mail::Wake) if c_stop.load(Ordering::Relaxed) => return Ok(()),
+                Ok(Mail::Wake) => (),
+                Ok(Mail::Data(val)) => {
+                    Self::handle(&c_ports, port, &c_sv, &c_exit, &c_counters, &func, val)?
+                }
+                Err(_) => return Ok(()),
             }
-            if *c_exit.lock().unwrap() == true {
+            if c_exit.load(Ordering::Relaxed) {
                 return Ok(());
             }
         });
-        w.threads[pz] = Arc::new(Some(j));
-        return Ok(());
+        w.threads[port.to_usize()].push(j);
+    }
+    /// run `func` on one message under supervision
+    fn handle<F>(
+        ports: &Ports,
+        port: Port,
+        sv: &Supervision,
+        exit: &AtomicBool,
+        counters: &Counters,
+        func: &F,
+        val: Data,
+    ) -> Result<()>
+    where
+        F: ?Sized + Fn(&Ports, Data) -> Result<()>,
+    {
+        let start = Instant::now();
+        let rv = catch_unwind(AssertUnwindSafe(|| func(ports, val)));
+        counters.record(start.elapsed());
+        let e = match rv {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(e)) => e,
+            Err(p) => Error::JoinError(p),
+        };
+        Self::failed(ports, port, sv, exit, e)
+    }
+    fn failed(
+        ports: &Ports,
+        port: Port,
+        sv: &Supervision,
+        exit: &AtomicBool,
+        e: Error,
+    ) -> Result<()> {
+        let action = sv.failed();
+        let error = match e {
+            Error::JoinError(ref p) => p.downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| p.downcast_ref::<String>().cloned())
+                .unwrap_or("panic".to_string()),
+            ref e => format!("{:?}", e),
+        };
+        error!("port {:?} failed with {:?}, {:?}", port, error, action);
+        let report = Report {
+            port: port,
+            error: error,
+            restarts: sv.restarts.load(Ordering::Relaxed),
+            action: action,
+        };
+        if ports.depth(Port::Supervisor) < MAX_REPORTS {
+            let _ = OTP::send(ports, Port::Supervisor, Data::Report(report));
+        }
+        match action {
+            Restart::Restart { .. } => Ok(()),
+            Restart::Escalate => Err(e),
+            Restart::StopAll => {
+                exit.store(true, Ordering::Relaxed);
+                let _ = OTP::send(ports, Port::Main, Data::Signal);
+                for i in 0..ports.len() {
+                    ports.wake(Port::from_usize(i));
+                }
+                Err(e)
+            }
+        }
+    }
+    /// handle for sending into the ports from outside of a source or listener
+    pub fn ports(&self) -> Ports {
+        self.lock.read().unwrap().ports.clone()
     }
     pub fn send(ports: &Ports, to: Port, m: Data) -> Result<()> {
-        ports[to.to_usize()]
-            .send(m)
-            .or_else(|_| Err(Error::SendError))
+        let t = ports.table.read().unwrap();
+        let r = t.routes.get(to.to_usize()).ok_or(Error::OTPError)?;
+        //counted first, the message can be handled before `send` returns
+        r.counters.sent.fetch_add(1, Ordering::Relaxed);
+        if r.pick(&m).send(Mail::Data(m)).is_err() {
+            r.counters.sent.fetch_sub(1, Ordering::Relaxed);
+            return Err(Error::SendError);
+        }
+        Ok(())
     }
-    pub fn join(&mut self) -> Result<()> {
+    /// snapshot the counters of every port
+    pub fn metrics(&self) -> Vec<Metrics> {
+        self.ports().metrics()
+    }
+    /// deterministic runtime only, call one source or handle one message,
+    /// returns the port that ran or `None` if nothing could run
+    pub fn step(&self) -> Result<Option<Port>> {
+        self.step_port(None)
+    }
+    fn step_port(&self, only: Option<Port>) -> Result<Option<Port>> {
+        let sched = self.sched.as_ref().ok_or(Error::OTPError)?;
+        if self.exit.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        let (ports, stops, sv) = {
+            let r = self.lock.read().unwrap();
+            (r.ports.clone(), r.stops.clone(), r.supervision.clone())
+        };
+        let mut s = sched.lock().unwrap();
+        let mut ready = Vec::new();
+        for (i, &mut (port, ref mut t)) in s.tasks.iter_mut().enumerate() {
+            if only.map(|p| p != port).unwrap_or(false) {
+                continue;
+            }
+            match *t {
+                Task::Source(_) => if !stops[port.to_usize()].load(Ordering::Relaxed) {
+                    ready.push(i);
+                },
+                Task::Listener(ref recv, _, ref mut pending) => {
+                    let recv = recv.lock().unwrap();
+                    while pending.is_none() {
+                        match recv.try_recv() {
+                            Ok(Mail::Data(d)) => *pending = Some(d),
+                            Ok(Mail::Wake) => (),
+                            Err(_) => break,
+                        }
+                    }
+                    if pending.is_some() {
+                        ready.push(i);
+                    }
+                }
+            }
+        }
+        if ready.is_empty() {
+            return Ok(None);
+        }
+        let ix = ready[s.rng.gen_range(0, ready.len())];
+        let port = s.tasks[ix].0;
+        let rv = match s.tasks[ix].1 {
+            Task::Source(ref func) => func(&ports),
+            Task::Listener(_, ref func, ref mut pending) => {
+                let val = pending.take().unwrap();
+                let pz = port.to_usize();
+                let counters = ports.counters(port);
+                Self::handle(&ports, port, &sv[pz], &self.exit, &counters, &**func, val)
+            }
+        };
+        if let Err(e) = rv {
+            s.tasks.remove(ix);
+            return Err(e);
+        }
+        Ok(Some(port))
+    }
+    /// block until something is sent to `Port::Main` or the node shuts down,
+    /// the deterministic runtime steps until then and fails if it gets stuck
+    pub fn wait(&self) -> Result<()> {
         let pz = Port::Main.to_usize();
-        let recv = self.lock.write().unwrap().readers[pz].clone();
+        let (recv, counters) = {
+            let w = self.lock.write().unwrap();
+            (w.readers[pz].clone(), w.ports.counters(Port::Main))
+        };
+        if self.sched.is_some() {
+            loop {
+                if recv.lock().unwrap().try_recv().is_ok() {
+                    counters.handled.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                if self.step()?.is_none() {
+                    return Err(Error::OTPError);
+                }
+            }
+        }
         recv.lock().unwrap().recv()?;
+        counters.handled.fetch_add(1, Ordering::Relaxed);
+        return Ok(());
+    }
+    pub fn join(&mut self) -> Result<()> {
+        self.wait()?;
         self.shutdown()?;
         return Ok(());
     }
+    /// stop the threads of `port`, sources stop after their current call and
+    /// listeners stop once their queue is empty
+    pub fn stop(&mut self, port: Port) -> Result<()> {
+        let mut w = self.lock.write().unwrap();
+        let pz = port.to_usize();
+        w.stops.get(pz).ok_or(Error::OTPError)?.store(true, Ordering::Relaxed);
+        w.ports.wake(port);
+        if let Some(ref sched) = self.sched {
+            drop(w);
+            let mut rv = Ok(());
+            while rv.is_ok() {
+                match self.step_port(Some(port)) {
+                    Ok(Some(_)) => (),
+                    Ok(None) => break,
+                    Err(e) => rv = Err(e),
+                }
+            }
+            sched.lock().unwrap().tasks.retain(|t| t.0 != port);
+            return rv;
+        }
+        let mut rv = Ok(());
+        for j in w.threads[pz].drain(..) {
+            let e = match j.join() {
+                Ok(r) => r,
+                Err(p) => Err(Error::JoinError(p)),
+            };
+            if rv.is_ok() {
+                rv = e;
+            }
+        }
+        rv
+    }
+    /// stop `order` one port at a time so each stage drains what the
+    /// previous one produced, then shut down the rest
+    pub fn drain(&mut self, order: &[Port]) -> Result<()> {
+        let mut rv = Ok(());
+        for port in order {
+            let e = self.stop(*port);
+            if rv.is_ok() {
+                rv = e;
+            }
+        }
+        let e = self.shutdown();
+        rv.and(e)
+    }
     pub fn shutdown(&mut self) -> Result<()> {
-        {
-            *self.exit.lock().unwrap() = true;
+        self.exit.store(true, Ordering::Relaxed);
+        if let Some(ref sched) = self.sched {
+            sched.lock().unwrap().tasks.clear();
         }
+        let mut rv = Ok(());
         {
-            let r = self.lock.read().unwrap();
-            for t in r.threads.iter() {
-                match Arc::try_unwrap((*t).clone()) {
-                    Ok(Some(j)) => j.join()??,
-                    _ => (),
-                };
+            let mut w = self.lock.write().unwrap();
+            for i in 0..w.threads.len() {
+                w.ports.wake(Port::from_usize(i));
+            }
+            for ts in w.threads.iter_mut() {
+                for j in ts.drain(..) {
+                    let e = match j.join() {
+                        Ok(r) => r,
+                        Err(p) => Err(Error::JoinError(p)),
+                    };
+                    if rv.is_ok() {
+                        rv = e;
+                    }
+                }
             }
         }
-        return Ok(());
+        return rv;
     }
 }
 
 #[cfg(test)]
 mod test {
-    use otp::OTP;
+    use otp::{Data, Dispatch, Port, Restart, MAX_REPORTS, OTP};
     use otp::Port::{Main, Reader, State};
-    use otp::Data::Signal;
+    use otp::Data::{SendMessage, Signal};
     use std::sync::{Arc, Mutex};
+    use std::thread::{current, sleep, ThreadId};
+    use std::time::{Duration, Instant};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use result::Error;
+    use std::net::SocketAddr;
+    use data::Message;
 
     #[test]
     fn test_init() {
@@ -186,8 +823,11 @@ mod test {
             o.source(Reader, move |ports| OTP::send(ports, Main, Signal)),
             Ok(())
         );
-        assert!(o.source(Reader, move |_ports| Ok(())).is_err());
+        assert_matches!(o.source(Reader, move |_ports| Ok(())), Ok(()));
         assert!(o.listen(Reader, move |_ports, _data| Ok(())).is_err());
+        assert!(o.listen(State, move |_ports, _data| Ok(())).is_ok());
+        assert!(o.source(State, move |_ports| Ok(())).is_err());
+        assert!(o.listen(State, move |_ports, _data| Ok(())).is_err());
         assert_matches!(o.join(), Ok(()));
     }
     #[test]
@@ -212,5 +852,384 @@ mod test {
         assert_matches!(o.join(), Ok(()));
         assert_eq!(*val.lock().unwrap(), true);
     }
+    #[test]
+    fn test_register() {
+        let mut o = OTP::new();
+        assert_eq!(o.port("state").unwrap(), State);
+        assert!(o.register("state").is_err());
+        assert!(o.port("verifier").is_err());
+        assert!(o.listen(Port::Id(100), move |_ports, _data| Ok(())).is_err());
+        let verifier = o.register("verifier").expect("register");
+        assert_eq!(o.port("verifier").unwrap(), verifier);
+        assert_eq!(o.ports().name(verifier).unwrap(), "verifier");
+        assert_matches!(
+            o.source(Reader, move |ports| match ports.lookup("ledger") {
+                Ok(to) => OTP::send(ports, to, Signal),
+                Err(_) => Ok(()),
+            }),
+            Ok(())
+        );
+        assert_matches!(
+            o.listen(verifier, move |ports, data| OTP::send(ports, Main, data)),
+            Ok(())
+        );
+        let ledger = o.register("ledger").expect("register");
+        assert_matches!(
+            o.listen(ledger, move |ports, data| {
+                let to = ports.lookup("verifier")?;
+                OTP::send(ports, to, data)
+            }),
+            Ok(())
+        );
+        assert_matches!(o.join(), Ok(()));
+    }
+    fn record_workers(o: &mut OTP, dispatch: Dispatch) -> Arc<Mutex<Vec<(usize, ThreadId)>>> {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let c_seen = seen.clone();
+        assert_matches!(
+            o.workers(State, 4, dispatch, move |ports, data| {
+                let key = match data {
+                    SendMessage(_, a) => a.port() as usize,
+                    _ => 0,
+                };
+                let mut v = c_seen.lock().unwrap();
+                v.push((key, current().id()));
+                if v.len() == 100 {
+                    OTP::send(ports, Main, Signal)?;
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        let ports = o.ports();
+        for i in 0..100 {
+            let addr = SocketAddr::from(([127, 0, 0, 1], i % 10));
+            OTP::send(&ports, State, SendMessage(Message::default(), addr)).unwrap();
+        }
+        seen
+    }
+    #[test]
+    fn test_workers_round_robin() {
+        let mut o = OTP::new();
+        let seen = record_workers(&mut o, Dispatch::RoundRobin);
+        assert!(o.listen(State, move |_ports, _data| Ok(())).is_err());
+        assert_matches!(o.join(), Ok(()));
+        let v = seen.lock().unwrap();
+        let mut threads: Vec<ThreadId> = v.iter().map(|&(_, t)| t).collect();
+        threads.sort_by_key(|t| format!("{:?}", t));
+        threads.dedup();
+        assert_eq!(threads.len(), 4);
+        for t in threads {
+            assert_eq!(v.iter().filter(|&&(_, x)| x == t).count(), 25);
+        }
+    }
+    #[test]
+    fn test_workers_key() {
+        fn key(d: &Data) -> usize {
+            match *d {
+                SendMessage(_, a) => a.port() as usize,
+                _ => 0,
+            }
+        }
+        let mut o = OTP::new();
+        let seen = record_workers(&mut o, Dispatch::Key(key));
+        assert_matches!(o.join(), Ok(()));
+        let v = seen.lock().unwrap();
+        for &(k, t) in v.iter() {
+            assert!(v.iter().filter(|&&(x, _)| x == k).all(|&(_, y)| y == t));
+        }
+    }
 
+    fn failing(o: &mut OTP, fail: usize) {
+        let count = AtomicUsize::new(0);
+        assert_matches!(
+            o.listen(State, move |ports, _data| {
+                let c = count.fetch_add(1, Ordering::Relaxed) + 1;
+                if c <= fail / 2 {
+                    return Err(Error::NoneError);
+                }
+                if c <= fail {
+                    panic!("bad message {}", c);
+                }
+                OTP::send(ports, Main, Signal)
+            }),
+            Ok(())
+        );
+        let ports = o.ports();
+        for _ in 0..fail + 1 {
+            OTP::send(&ports, State, Signal).unwrap();
+        }
+    }
+    #[test]
+    fn test_restart() {
+        let mut o = OTP::new();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let c_reports = reports.clone();
+        assert_matches!(
+            o.listen(Port::Supervisor, move |_ports, data| match data {
+                Data::Report(r) => {
+                    c_reports.lock().unwrap().push(r);
+                    Ok(())
+                }
+                _ => Ok(()),
+            }),
+            Ok(())
+        );
+        failing(&mut o, 4);
+        //a report is sent before the restart, give the supervisor time to
+        //take them all before shutdown stops it
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reports.lock().unwrap().len() < 4 && Instant::now() < deadline {
+            sleep(Duration::from_millis(1));
+        }
+        assert_matches!(o.join(), Ok(()));
+        assert_eq!(o.restarts(State).unwrap(), 4);
+        let v = reports.lock().unwrap();
+        assert_eq!(v.len(), 4);
+        assert_eq!(v[0].port, State);
+        assert_eq!(v[0].error, "NoneError");
+        assert_eq!(v[3].error, "bad message 4");
+        assert_eq!(v[3].restarts, 4);
+    }
+    fn signal_escalation(o: &mut OTP) {
+        assert_matches!(
+            o.listen(Port::Supervisor, move |ports, data| match data {
+                Data::Report(ref r) if r.action == Restart::Escalate => {
+                    OTP::send(ports, Main, Signal)
+                }
+                _ => Ok(()),
+            }),
+            Ok(())
+        );
+    }
+    #[test]
+    fn test_escalate() {
+        let mut o = OTP::new();
+        o.supervise(State, Restart::Escalate).unwrap();
+        signal_escalation(&mut o);
+        failing(&mut o, 2);
+        assert_matches!(o.join(), Err(Error::NoneError));
+        assert_eq!(o.restarts(State).unwrap(), 0);
+    }
+    #[test]
+    fn test_restart_limit() {
+        let mut o = OTP::new();
+        let policy = Restart::Restart {
+            max: 2,
+            period: Duration::new(60, 0),
+        };
+        o.supervise(State, policy).unwrap();
+        signal_escalation(&mut o);
+        failing(&mut o, 4);
+        assert_matches!(o.join(), Err(Error::JoinError(_)));
+        assert_eq!(o.restarts(State).unwrap(), 2);
+    }
+    #[test]
+    fn test_stop_all() {
+        let mut o = OTP::new();
+        o.supervise(State, Restart::StopAll).unwrap();
+        assert!(o.source(Reader, move |_ports| Ok(())).is_ok());
+        failing(&mut o, 2);
+        assert_matches!(o.join(), Err(Error::NoneError));
+    }
+
+    #[test]
+    fn test_metrics() {
+        let mut o = OTP::new();
+        let idle = o.register("idle").expect("register");
+        assert_matches!(
+            o.listen(State, move |ports, data| {
+                sleep(Duration::from_millis(2));
+                OTP::send(ports, Main, data)
+            }),
+            Ok(())
+        );
+        let ports = o.ports();
+        for _ in 0..10 {
+            OTP::send(&ports, State, Signal).unwrap();
+            OTP::send(&ports, idle, Signal).unwrap();
+        }
+        for _ in 0..10 {
+            assert_matches!(o.wait(), Ok(()));
+        }
+        assert_matches!(o.shutdown(), Ok(()));
+        let m = o.metrics();
+        assert_eq!(m.len(), 7);
+        let state = &m[State.to_usize()];
+        assert_eq!(state.name, "state");
+        assert_eq!((state.sent, state.handled, state.depth), (10, 10, 0));
+        assert_eq!(state.handler.iter().sum::<usize>(), 10);
+        assert!(state.quantile(0.5) >= Duration::from_millis(2));
+        let idle = &m[idle.to_usize()];
+        assert_eq!((idle.sent, idle.handled, idle.depth), (10, 0, 10));
+        assert_eq!(idle.quantile(0.99), Duration::new(0, 0));
+        let main = &m[Main.to_usize()];
+        assert_eq!((main.sent, main.handled, main.depth), (10, 10, 0));
+    }
+
+    #[test]
+    fn test_reports_bounded() {
+        let mut o = OTP::deterministic(0);
+        let policy = Restart::Restart {
+            max: usize::max_value(),
+            period: Duration::new(1, 0),
+        };
+        assert_matches!(o.supervise(State, policy), Ok(()));
+        assert_matches!(o.listen(State, move |_ports, _data| Err(Error::OTPError)), Ok(()));
+        let ports = o.ports();
+        for _ in 0..MAX_REPORTS + 10 {
+            OTP::send(&ports, State, Signal).unwrap();
+            assert_matches!(o.step(), Ok(Some(State)));
+        }
+        //nothing listens on the supervisor
+        assert_eq!(o.restarts(State).unwrap(), MAX_REPORTS + 10);
+        let m = o.metrics();
+        assert_eq!(m[Port::Supervisor.to_usize()].sent, MAX_REPORTS);
+    }
+    #[test]
+    fn test_step() {
+        let mut o = OTP::deterministic(0);
+        let count = Arc::new(AtomicUsize::new(0));
+        let c_count = count.clone();
+        assert_matches!(
+            o.listen(State, move |ports, data| OTP::send(ports, Port::Sender, data)),
+            Ok(())
+        );
+        assert_matches!(
+            o.listen(Port::Sender, move |_ports, _data| {
+                c_count.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }),
+            Ok(())
+        );
+        assert_matches!(o.step(), Ok(None));
+        let ports = o.ports();
+        OTP::send(&ports, State, Signal).unwrap();
+        assert_matches!(o.step(), Ok(Some(State)));
+        assert_eq!(count.load(Ordering::Relaxed), 0);
+        assert_matches!(o.step(), Ok(Some(Port::Sender)));
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        for _ in 0..3 {
+            OTP::send(&ports, State, Signal).unwrap();
+        }
+        let mut steps = 0;
+        while let Some(_) = o.step().unwrap() {
+            steps += 1;
+        }
+        assert_eq!(steps, 6);
+        assert_eq!(count.load(Ordering::Relaxed), 4);
+        assert!(OTP::new().step().is_err());
+        assert_matches!(o.shutdown(), Ok(()));
+    }
+    fn trace(seed: usize) -> Vec<Port> {
+        let o = OTP::deterministic(seed);
+        assert_matches!(
+            o.source(Reader, move |ports| OTP::send(ports, State, Signal)),
+            Ok(())
+        );
+        assert_matches!(
+            o.source(Port::Recycle, move |ports| OTP::send(ports, State, Signal)),
+            Ok(())
+        );
+        (0..32).map(|_| o.step().unwrap().unwrap()).collect()
+    }
+    #[test]
+    fn test_seeded() {
+        assert_eq!(trace(1), trace(1));
+        assert_ne!(trace(1), trace(2));
+    }
+    #[test]
+    fn test_deterministic_join() {
+        let mut o = OTP::deterministic(3);
+        o.supervise(State, Restart::StopAll).unwrap();
+        failing(&mut o, 2);
+        assert_matches!(o.join(), Err(Error::NoneError));
+        let mut o = OTP::deterministic(3);
+        failing(&mut o, 2);
+        assert_matches!(o.join(), Ok(()));
+        assert_eq!(o.restarts(State).unwrap(), 2);
+        let mut o = OTP::deterministic(3);
+        assert_matches!(o.listen(State, move |_ports, _data| Ok(())), Ok(()));
+        assert_matches!(o.join(), Err(Error::OTPError));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut o = OTP::new();
+        let count = Arc::new(AtomicUsize::new(0));
+        let c_count = count.clone();
+        assert_matches!(
+            o.listen(State, move |ports, data| {
+                sleep(Duration::from_millis(1));
+                OTP::send(ports, Port::Sender, data)
+            }),
+            Ok(())
+        );
+        assert_matches!(
+            o.listen(Port::Sender, move |_ports, _data| {
+                c_count.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }),
+            Ok(())
+        );
+        let ports = o.ports();
+        for _ in 0..100 {
+            OTP::send(&ports, State, Signal).unwrap();
+        }
+        assert_matches!(o.drain(&[Reader, State, Port::Sender]), Ok(()));
+        assert_eq!(count.load(Ordering::Relaxed), 100);
+    }
+
+}
+
+#[cfg(all(feature = "unstable", test))]
+mod bench {
+    extern crate test;
+    use self::test::Bencher;
+    use otp::{Port, OTP};
+    use otp::Data::Signal;
+    use std::thread::sleep;
+    use std::time::{Duration, Instant};
+    use nix::libc;
+
+    fn cpu_time() -> Duration {
+        let mut ru: libc::rusage = unsafe { ::std::mem::zeroed() };
+        unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut ru) };
+        let usec = |t: libc::timeval| t.tv_sec as u64 * 1_000_000 + t.tv_usec as u64;
+        Duration::from_micros(usec(ru.ru_utime) + usec(ru.ru_stime))
+    }
+
+    /// one round trip is 3 hops, State -> Sender -> Main
+    #[bench]
+    fn hop_bench(b: &mut Bencher) {
+        let mut o = OTP::new();
+        o.listen(Port::State, move |ports, data| OTP::send(ports, Port::Sender, data))
+            .expect("listen");
+        o.listen(Port::Sender, move |ports, data| OTP::send(ports, Port::Main, data))
+            .expect("listen");
+        let ports = o.ports();
+        b.iter(|| {
+            OTP::send(&ports, Port::State, Signal).expect("send");
+            o.wait().expect("wait");
+        });
+        o.shutdown().expect("shutdown");
+    }
+    /// wall time is the sleep, the cpu burned by 64 idle listeners is logged,
+    /// run with `RUST_LOG=info cargo bench --features unstable`, idle
+    /// listeners block so it has to stay well under the wall time
+    #[bench]
+    fn idle_bench(b: &mut Bencher) {
+        let mut o = OTP::new();
+        for i in 0..64 {
+            let port = o.register(&format!("idle{}", i)).expect("register");
+            o.listen(port, move |_ports, _data| Ok(())).expect("listen");
+        }
+        let start = Instant::now();
+        let cpu = cpu_time();
+        b.iter(|| sleep(Duration::from_millis(10)));
+        let (idle, wall) = (cpu_time() - cpu, start.elapsed());
+        info!("idle cpu {:?} over {:?}", idle, wall);
+        assert!(idle < wall / 2, "idle cpu {:?} over {:?}", idle, wall);
+        o.shutdown().expect("shutdown");
+    }
 }
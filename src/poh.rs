@@ -1,25 +1,57 @@
-use data;
-use hasht;
-use result;
+//! proof of history, a sha256 hash chain that counts every hash and mixes
+//! in the data recorded into it
 
-struct Subscriber {
-    key: [u8; 32],
-    addr: SockAddr,
-    lastping: u64,
-}
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
 
-struct Gossip {
-    subscribers: Vec<Subscriber>,
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct Poh {
+    pub hash: [u8; 32],
+    pub count: u64,
 }
 
-impl Gossip {
-    pub fn execute(&self, msgs: &mut [data::Message]) {
-        for m in msgs {
-            if m.kind == GossipSubscribe {
-                let pos = hasht::find(&self.subscribers, m.data.subs.key)?;
-                let update = Subscriber{m.data.subs.addr, m.data.key, now};
-                *self.subscribers.get_unchecked_mut(pos) = update;
-            }
+impl Poh {
+    pub fn new(hash: [u8; 32]) -> Poh {
+        Poh {
+            hash: hash,
+            count: 0,
+        }
+    }
+    /// hash `num` times without recording anything
+    pub fn tick(&mut self, num: u64) {
+        for _ in 0..num {
+            self.next(&[]);
         }
     }
+    /// mix `data` into the chain, returns the count of the entry that holds it
+    pub fn record(&mut self, data: &[u8]) -> u64 {
+        self.next(data);
+        self.count
+    }
+    fn next(&mut self, data: &[u8]) {
+        let mut h = Sha256::new();
+        h.input(&self.hash);
+        h.input(data);
+        h.result(&mut self.hash);
+        self.count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use poh::Poh;
+
+    #[test]
+    fn poh_test() {
+        let mut a = Poh::new([1u8; 32]);
+        let mut b = a;
+        a.tick(2);
+        assert_eq!(a.count, 2);
+        assert_eq!(b.record(&[]), 1);
+        assert_eq!(b.record(&[]), 2);
+        assert_eq!(a, b);
+        assert_eq!(a.record(&[3]), 3);
+        assert_eq!(b.record(&[4]), 3);
+        assert_ne!(a.hash, b.hash);
+    }
 }
@@ -0,0 +1,149 @@
+//! fee priority queue between the readers and State
+//!
+//! batches wait in the queue while State is busy, and the one paying the
+//! highest fee per message runs next, batches with the same fee run in
+//! arrival order
+
+use data;
+use otp::{Data, Port, Ports, OTP};
+use result::Result;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Mutex;
+
+struct Pending {
+    fee: u64,
+    seq: usize,
+    msgs: data::SharedMessages,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Pending) -> bool {
+        self.fee == other.fee && self.seq == other.seq
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Pending) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    fn cmp(&self, other: &Pending) -> Ordering {
+        self.fee
+            .cmp(&other.fee)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+#[derive(Default)]
+struct Heap {
+    pending: BinaryHeap<Pending>,
+    seq: usize,
+}
+
+#[derive(Default)]
+pub struct Queue {
+    heap: Mutex<Heap>,
+}
+
+/// average fee of the messages in `m`
+pub fn fee_per_message(m: &data::SharedMessages) -> u64 {
+    let v = m.read().unwrap();
+    if v.msgs.is_empty() {
+        return 0;
+    }
+    let total = v.msgs
+        .iter()
+        .fold(0u64, |t, m| t.saturating_add(m.pld.fee));
+    total / v.msgs.len() as u64
+}
+
+impl Queue {
+    pub fn new() -> Queue {
+        Queue::default()
+    }
+    /// queue a batch and signal `to` once for it, `to` calls `pop`
+    pub fn push(&self, ports: &Ports, to: Port, d: Data) -> Result<()> {
+        if let Data::SharedMessages(m) = d {
+            let fee = fee_per_message(&m);
+            {
+                let mut h = self.heap.lock().unwrap();
+                let seq = h.seq;
+                h.seq += 1;
+                h.pending.push(Pending {
+                    fee: fee,
+                    seq: seq,
+                    msgs: m,
+                });
+            }
+            OTP::send(ports, to, Data::Signal)?;
+        }
+        Ok(())
+    }
+    /// the batch with the highest fee per message
+    pub fn pop(&self) -> Option<data::SharedMessages> {
+        self.heap.lock().unwrap().pending.pop().map(|p| p.msgs)
+    }
+    pub fn len(&self) -> usize {
+        self.heap.lock().unwrap().pending.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data;
+    use otp::{Data, Port, OTP};
+    use priority::{fee_per_message, Queue};
+    use std::sync::{Arc, RwLock};
+
+    fn batch(fees: &[u64]) -> data::SharedMessages {
+        let mut m = data::Messages::new();
+        m.msgs.truncate(fees.len());
+        for (m, f) in m.msgs.iter_mut().zip(fees) {
+            m.pld.fee = *f;
+        }
+        Arc::new(RwLock::new(m))
+    }
+    #[test]
+    fn fee_test() {
+        assert_eq!(fee_per_message(&batch(&[])), 0);
+        assert_eq!(fee_per_message(&batch(&[1, 2, 6])), 3);
+        assert_eq!(fee_per_message(&batch(&[u64::max_value(), 2])), u64::max_value() / 2);
+    }
+    #[test]
+    fn queue_test() {
+        let o = OTP::deterministic(0);
+        let ports = o.ports();
+        let q = Queue::new();
+        for fees in [[1, 1], [5, 3], [1, 1], [2, 2]].iter() {
+            q.push(&ports, Port::State, Data::SharedMessages(batch(fees)))
+                .expect("push");
+        }
+        assert_eq!(q.len(), 4);
+        let order: Vec<u64> = (0..4).map(|_| fee_per_message(&q.pop().unwrap())).collect();
+        assert_eq!(order, vec![4, 2, 1, 1]);
+        assert!(q.is_empty());
+        assert!(q.pop().is_none());
+    }
+    #[test]
+    fn queue_order_test() {
+        let o = OTP::deterministic(0);
+        let ports = o.ports();
+        let q = Queue::new();
+        let a = batch(&[1]);
+        let b = batch(&[1]);
+        q.push(&ports, Port::State, Data::SharedMessages(a.clone()))
+            .expect("push");
+        q.push(&ports, Port::State, Data::SharedMessages(b.clone()))
+            .expect("push");
+        assert!(Arc::ptr_eq(&q.pop().unwrap(), &a));
+        assert!(Arc::ptr_eq(&q.pop().unwrap(), &b));
+    }
+}
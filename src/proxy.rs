@@ -0,0 +1,317 @@
+//! lossy udp proxy for integration tests
+//!
+//! sits between a client and a loomd instance and drops, duplicates, reorders,
+//! delays and truncates datagrams according to a seeded `Policy`
+
+use std::cmp::max;
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread::{spawn, JoinHandle};
+use std::time::{Duration, Instant};
+use rand::{Rng, SeedableRng, StdRng};
+use data::MAX_PACKET;
+use result::Result;
+
+/// probabilities are in the range 0.0 to 1.0 and are rolled per datagram
+#[derive(Clone, Debug)]
+pub struct Policy {
+    pub seed: usize,
+    pub drop: f64,
+    pub duplicate: f64,
+    pub reorder: f64,
+    pub truncate: f64,
+    pub delay: f64,
+    /// upper bound for a delayed datagram
+    pub max_delay: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Policy {
+        Policy {
+            seed: 0,
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            truncate: 0.0,
+            delay: 0.0,
+            max_delay: Duration::new(0, 0),
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct Stats {
+    pub received: usize,
+    pub forwarded: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub truncated: usize,
+    pub delayed: usize,
+}
+
+/// how long a reordered datagram waits for a successor before it is sent anyway
+const HOLD: u64 = 10;
+
+struct Lane {
+    rng: StdRng,
+    policy: Policy,
+    queue: VecDeque<(Instant, Vec<u8>)>,
+    held: Option<(Instant, Vec<u8>)>,
+}
+
+impl Lane {
+    fn new(policy: Policy, seed: usize) -> Lane {
+        Lane {
+            rng: StdRng::from_seed(&[seed]),
+            policy: policy,
+            queue: VecDeque::new(),
+            held: None,
+        }
+    }
+    fn roll(&mut self, p: f64) -> bool {
+        p > 0.0 && self.rng.gen::<f64>() < p
+    }
+    fn push(&mut self, mut pkt: Vec<u8>, stats: &mut Stats) {
+        let now = Instant::now();
+        stats.received += 1;
+        if self.roll(self.policy.drop) {
+            stats.dropped += 1;
+            return;
+        }
+        if !pkt.is_empty() && self.roll(self.policy.truncate) {
+            let len = self.rng.gen_range(0, pkt.len());
+            pkt.truncate(len);
+            stats.truncated += 1;
+        }
+        let mut at = now;
+        if self.roll(self.policy.delay) {
+            let max = self.policy.max_delay;
+            let ms = max.as_secs() * 1000 + u64::from(max.subsec_nanos()) / 1_000_000;
+            at += Duration::from_millis(self.rng.gen_range(0, ms + 1));
+            stats.delayed += 1;
+        }
+        if self.roll(self.policy.duplicate) {
+            self.insert(at, pkt.clone());
+            stats.duplicated += 1;
+        }
+        if self.held.is_none() && self.roll(self.policy.reorder) {
+            self.held = Some((at, pkt));
+            stats.reordered += 1;
+            return;
+        }
+        self.insert(at, pkt);
+        //the held datagram goes out no earlier than the one that passed it
+        if let Some((held_at, held)) = self.held.take() {
+            self.insert(max(at, held_at), held);
+        }
+    }
+    /// queue `pkt` after everything due at or before `at`
+    fn insert(&mut self, at: Instant, pkt: Vec<u8>) {
+        let pos = self.queue
+            .iter()
+            .position(|&(t, _)| t > at)
+            .unwrap_or(self.queue.len());
+        self.queue.insert(pos, (at, pkt));
+    }
+    fn flush(&mut self, sock: &UdpSocket, to: SocketAddr, stats: &mut Stats) -> Result<()> {
+        let now = Instant::now();
+        let expired = match self.held {
+            Some((at, _)) => now.duration_since(at) > Duration::from_millis(HOLD),
+            None => false,
+        };
+        if expired {
+            let (at, held) = self.held.take().unwrap();
+            self.insert(at, held);
+        }
+        while self.queue.front().map(|&(t, _)| t <= now).unwrap_or(false) {
+            let (_, pkt) = self.queue.pop_front().unwrap();
+            sock.send_to(&pkt, &to)?;
+            stats.forwarded += 1;
+        }
+        Ok(())
+    }
+}
+
+pub struct Proxy {
+    addr: SocketAddr,
+    stats: Arc<Mutex<Stats>>,
+    exit: Arc<Mutex<bool>>,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl Proxy {
+    /// listen on a loopback port and forward to `target`, replies are sent
+    /// back to the last client that sent a datagram
+    pub fn new(target: SocketAddr, policy: Policy) -> Result<Proxy> {
+        let front = UdpSocket::bind("127.0.0.1:0")?;
+        let back = UdpSocket::bind("0.0.0.0:0")?;
+        let timer = Duration::from_millis(1);
+        front.set_read_timeout(Some(timer))?;
+        back.set_read_timeout(Some(timer))?;
+        let addr = front.local_addr()?;
+        let stats = Arc::new(Mutex::new(Stats::default()));
+        let exit = Arc::new(Mutex::new(false));
+        let c_stats = stats.clone();
+        let c_exit = exit.clone();
+        let mut up = Lane::new(policy.clone(), policy.seed);
+        let mut down = Lane::new(policy.clone(), policy.seed.wrapping_add(1));
+        let j = spawn(move || {
+            let mut client = None;
+            let mut buf = vec![0u8; MAX_PACKET];
+            loop {
+                if *c_exit.lock().unwrap() {
+                    return Ok(());
+                }
+                if let Ok((nrecv, from)) = front.recv_from(&mut buf) {
+                    client = Some(from);
+                    up.push(buf[..nrecv].to_vec(), &mut c_stats.lock().unwrap());
+                }
+                if let Ok((nrecv, from)) = back.recv_from(&mut buf) {
+                    if from == target {
+                        down.push(buf[..nrecv].to_vec(), &mut c_stats.lock().unwrap());
+                    }
+                }
+                up.flush(&back, target, &mut c_stats.lock().unwrap())?;
+                if let Some(c) = client {
+                    down.flush(&front, c, &mut c_stats.lock().unwrap())?;
+                }
+            }
+        });
+        Ok(Proxy {
+            addr: addr,
+            stats: stats,
+            exit: exit,
+            thread: Some(j),
+        })
+    }
+    /// address the client should send to
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+    pub fn stats(&self) -> Stats {
+        *self.stats.lock().unwrap()
+    }
+    pub fn shutdown(&mut self) -> Result<()> {
+        *self.exit.lock().unwrap() = true;
+        if let Some(j) = self.thread.take() {
+            j.join()??;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proxy::{Policy, Proxy};
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn echo() -> UdpSocket {
+        let s = UdpSocket::bind("127.0.0.1:0").expect("bind");
+        s.set_read_timeout(Some(Duration::from_millis(200)))
+            .expect("timer");
+        s
+    }
+    fn client() -> UdpSocket {
+        echo()
+    }
+    fn recv_all(s: &UdpSocket) -> Vec<Vec<u8>> {
+        let mut rv = Vec::new();
+        let mut buf = [0u8; 64];
+        while let Ok((n, _)) = s.recv_from(&mut buf) {
+            rv.push(buf[..n].to_vec());
+        }
+        rv
+    }
+    fn send_all(s: &UdpSocket, p: &Proxy, num: u8) {
+        for i in 0..num {
+            s.send_to(&[i; 8], &p.addr()).expect("send");
+        }
+    }
+
+    #[test]
+    fn passthrough_test() {
+        let srv = echo();
+        let cli = client();
+        let p = Proxy::new(srv.local_addr().unwrap(), Policy::default()).expect("proxy");
+        send_all(&cli, &p, 16);
+        let mut buf = [0u8; 64];
+        let (_, back) = srv.recv_from(&mut buf).expect("first");
+        let mut got = recv_all(&srv);
+        got.insert(0, buf[..8].to_vec());
+        let want: Vec<Vec<u8>> = (0..16).map(|i| vec![i; 8]).collect();
+        assert_eq!(got, want);
+        srv.send_to(&[7; 4], &back).expect("reply");
+        let (n, from) = cli.recv_from(&mut buf).expect("reply");
+        assert_eq!(&buf[..n], &[7; 4]);
+        assert_eq!(from, p.addr());
+    }
+    #[test]
+    fn drop_test() {
+        let srv = echo();
+        let cli = client();
+        let mut policy = Policy::default();
+        policy.drop = 1.0;
+        let p = Proxy::new(srv.local_addr().unwrap(), policy).expect("proxy");
+        send_all(&cli, &p, 16);
+        assert!(recv_all(&srv).is_empty());
+        assert_eq!(p.stats().dropped, 16);
+    }
+    #[test]
+    fn duplicate_truncate_test() {
+        let srv = echo();
+        let cli = client();
+        let mut policy = Policy::default();
+        policy.duplicate = 1.0;
+        policy.truncate = 1.0;
+        let p = Proxy::new(srv.local_addr().unwrap(), policy).expect("proxy");
+        send_all(&cli, &p, 4);
+        let got = recv_all(&srv);
+        assert_eq!(got.len(), 8);
+        for g in got {
+            assert!(g.len() < 8);
+        }
+    }
+    #[test]
+    fn reorder_test() {
+        let srv = echo();
+        let cli = client();
+        let mut policy = Policy::default();
+        policy.reorder = 1.0;
+        let p = Proxy::new(srv.local_addr().unwrap(), policy).expect("proxy");
+        send_all(&cli, &p, 8);
+        let got = recv_all(&srv);
+        //every other datagram is held until its successor went out
+        let want: Vec<Vec<u8>> = [1, 0, 3, 2, 5, 4, 7, 6].iter().map(|&i| vec![i; 8]).collect();
+        assert_eq!(got, want);
+        assert_eq!(p.stats().reordered, 4);
+    }
+    #[test]
+    fn reorder_delay_test() {
+        let srv = echo();
+        let cli = client();
+        let mut policy = Policy::default();
+        policy.seed = 42;
+        policy.reorder = 0.5;
+        policy.delay = 0.5;
+        policy.max_delay = Duration::from_millis(20);
+        let p = Proxy::new(srv.local_addr().unwrap(), policy).expect("proxy");
+        send_all(&cli, &p, 32);
+        let mut got = recv_all(&srv);
+        let stats = p.stats();
+        assert!(stats.reordered > 0);
+        assert!(stats.delayed > 0);
+        let ordered: Vec<Vec<u8>> = (0..32).map(|i| vec![i; 8]).collect();
+        assert_ne!(got, ordered);
+        got.sort();
+        assert_eq!(got, ordered);
+    }
+}
@@ -1,4 +1,5 @@
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
 use result::Result;
 use result::Error::IO;
@@ -11,8 +12,45 @@ use std::os::unix::io::FromRawFd;
 use std::os::unix::io::AsRawFd;
 use nix::unistd::dup;
 
+/// what the reader does when every buffer in the pool is in flight
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Backpressure {
+    /// read and discard packets until a buffer is recycled
+    Drop,
+    /// stop reading and let the kernel queue fill up
+    Block,
+}
+
+pub const DEFAULT_POOL: usize = 64;
+
+/// id of the next reader, the buffers are tagged with it
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct PoolStats {
+    /// maximum number of buffers the pool will allocate
+    pub max: usize,
+    /// buffers allocated so far
+    pub allocated: usize,
+    /// buffers waiting in the pool
+    pub free: usize,
+    /// packets read and discarded while the pool was empty
+    pub dropped: usize,
+    /// times the reader waited for a buffer to be recycled
+    pub blocked: usize,
+}
+
+struct Pool {
+    free: Vec<data::SharedMessages>,
+    policy: Backpressure,
+    stats: PoolStats,
+}
+
 pub struct Reader {
-    lock: Mutex<Vec<data::SharedMessages>>,
+    id: usize,
+    lock: Mutex<Pool>,
+    cond: Condvar,
+    scratch: Mutex<data::Messages>,
     sock: UdpSocket,
 }
 impl Reader {
@@ -30,25 +68,84 @@ impl Reader {
     pub fn new(port: u16) -> Result<Reader> {
         let ipv4 = Ipv4Addr::new(0, 0, 0, 0);
         let addr = SocketAddr::new(IpAddr::V4(ipv4), port);
+        Self::bind(addr)
+    }
+    pub fn bind(addr: SocketAddr) -> Result<Reader> {
         let srv = UdpSocket::bind(&addr)?;
+        Self::from_socket(srv)
+    }
+    /// open `num` readers sharing `addr` with SO_REUSEPORT, the kernel spreads
+    /// incoming packets across their sockets
+    pub fn reuseport(addr: SocketAddr, num: usize) -> Result<Vec<Reader>> {
+        let mut rv = Vec::new();
+        for _ in 0..num {
+            let srv = net::bind_reuseport(addr)?;
+            rv.push(Self::from_socket(srv)?);
+        }
+        Ok(rv)
+    }
+    fn from_socket(srv: UdpSocket) -> Result<Reader> {
         let timer = Duration::new(1, 0);
         srv.set_read_timeout(Some(timer))?;
+        let pool = Pool {
+            free: Vec::new(),
+            policy: Backpressure::Drop,
+            stats: PoolStats {
+                max: DEFAULT_POOL,
+                ..PoolStats::default()
+            },
+        };
         let rv = Reader {
-            lock: Mutex::new(Vec::new()),
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            lock: Mutex::new(pool),
+            cond: Condvar::new(),
+            scratch: Mutex::new(data::Messages::new()),
             sock: srv,
         };
         return Ok(rv);
     }
+    /// bound the number of buffers this reader allocates
+    pub fn set_pool(&self, max: usize, policy: Backpressure) {
+        let mut pool = self.lock.lock().expect("lock");
+        pool.stats.max = max;
+        pool.policy = policy;
+    }
+    pub fn stats(&self) -> PoolStats {
+        let pool = self.lock.lock().expect("lock");
+        let mut rv = pool.stats;
+        rv.free = pool.free.len();
+        rv
+    }
     pub fn recycle(&self, d: Data) {
         match d {
             Data::SharedMessages(m) => {
-                let mut gc = self.lock.lock().expect("lock");
-                gc.push(m);
+                let mut pool = self.lock.lock().expect("lock");
+                pool.free.push(m);
+                self.cond.notify_one();
             }
             _ => (),
         }
     }
 
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.sock.local_addr()?)
+    }
+    /// number of buffers waiting in the recycle pool
+    pub fn pool_len(&self) -> usize {
+        self.lock.lock().expect("lock").free.len()
+    }
+    /// return a buffer to the reader that allocated it, so each pool stays
+    /// within its own bound, buffers of no reader in `readers` are dropped
+    pub fn recycle_to(readers: &[Arc<Reader>], d: Data) {
+        let owner = match d {
+            Data::SharedMessages(ref m) => m.read().expect("read").owner,
+            _ => return,
+        };
+        if let Some(r) = readers.iter().find(|r| r.id == owner) {
+            r.recycle(d);
+        }
+    }
+
     fn read(&self, m: data::SharedMessages) -> Result<usize> {
         let mut v = m.write().unwrap();
         const SIZE: usize = 1024;
@@ -57,8 +154,25 @@ impl Reader {
         v.with(move |ms, ds| net::read_from(&self.sock, ms, ds))
     }
 
+    fn discard(&self) -> Result<()> {
+        let mut scratch = self.scratch.lock().expect("scratch");
+        let num = scratch.with_mut(|ms, ds| net::read_from(&self.sock, ms, ds));
+        if let Ok(num) = num {
+            let mut pool = self.lock.lock().expect("lock");
+            pool.stats.dropped += num;
+            debug!("pool empty, dropped {:?} packets", num);
+        }
+        Ok(())
+    }
+
     pub fn run(&self, ports: &Ports) -> Result<()> {
-        let m = self.allocate();
+        let m = match self.allocate() {
+            Some(m) => m,
+            None if self.lock.lock().expect("lock").policy == Backpressure::Drop => {
+                return self.discard();
+            }
+            None => return Ok(()),
+        };
         let mut total = 0usize;
         {
             trace!("reading");
@@ -87,15 +201,36 @@ impl Reader {
             OTP::send(ports, Port::State, Data::SharedMessages(m))?;
             return Ok(());
         } else {
-            let mut gc = self.lock.lock().expect("lock");
-            gc.push(m);
+            let mut pool = self.lock.lock().expect("lock");
+            pool.free.push(m);
             return Ok(());
         }
     }
-    fn allocate(&self) -> data::SharedMessages {
-        let mut gc = self.lock.lock().expect("lock");
-        gc.pop()
-            .unwrap_or_else(|| Arc::new(RwLock::new(data::Messages::new())))
+    /// returns None when the pool is exhausted and the policy is `Drop`, or
+    /// when a `Block` wait timed out so the caller can check for shutdown
+    fn allocate(&self) -> Option<data::SharedMessages> {
+        let mut pool = self.lock.lock().expect("lock");
+        loop {
+            if let Some(m) = pool.free.pop() {
+                return Some(m);
+            }
+            if pool.stats.allocated < pool.stats.max {
+                pool.stats.allocated += 1;
+                let mut m = data::Messages::new();
+                m.owner = self.id;
+                return Some(Arc::new(RwLock::new(m)));
+            }
+            if pool.policy == Backpressure::Drop {
+                return None;
+            }
+            pool.stats.blocked += 1;
+            let timer = Duration::new(0, 100000000);
+            let (p, t) = self.cond.wait_timeout(pool, timer).expect("wait");
+            pool = p;
+            if t.timed_out() && pool.free.is_empty() {
+                return None;
+            }
+        }
     }
 }
 
@@ -105,7 +240,7 @@ mod test {
     use otp::{Data, Port, OTP};
     use std::sync::{Arc, Mutex};
     use std::net::UdpSocket;
-    use reader::Reader;
+    use reader::{Backpressure, Reader};
     use std::time::Duration;
     use net;
     use data;
@@ -162,4 +297,105 @@ mod test {
         assert!(o.shutdown().is_ok());
         assert_eq!(*rvs.lock().unwrap(), 64);
     }
+
+    fn pool_test(port: u16, policy: Backpressure) -> Reader {
+        let reader = Reader::new(port).expect("reader");
+        reader.set_pool(1, policy);
+        let mut o = OTP::new();
+        let ports = o.ports();
+        let cli: UdpSocket = net::socket().expect("socket");
+        cli.connect(("127.0.0.1", port)).expect("client");
+        let m = [data::Message::default(); 4];
+        let mut num = 0;
+        while num < m.len() {
+            net::write(&cli, &m, &mut num).expect("write");
+        }
+        reader.run(&ports).expect("run");
+        let s = reader.stats();
+        assert_eq!(s.allocated, 1);
+        assert_eq!(s.free, 0);
+        let mut num = 0;
+        while num < m.len() {
+            net::write(&cli, &m, &mut num).expect("write");
+        }
+        reader.run(&ports).expect("run");
+        assert!(o.shutdown().is_ok());
+        reader
+    }
+
+    #[test]
+    fn pool_drop_test() {
+        let reader = pool_test(12004, Backpressure::Drop);
+        let s = reader.stats();
+        assert_eq!(s.allocated, 1);
+        assert_eq!(s.dropped, 1);
+        assert_eq!(s.blocked, 0);
+    }
+
+    #[test]
+    fn pool_block_test() {
+        let reader = pool_test(12005, Backpressure::Block);
+        let s = reader.stats();
+        assert_eq!(s.allocated, 1);
+        assert_eq!(s.dropped, 0);
+        assert_eq!(s.blocked, 1);
+    }
+
+    #[test]
+    fn reuseport_test() {
+        let addr = "127.0.0.1:12003".parse().expect("parse");
+        let readers: Vec<Arc<Reader>> = Reader::reuseport(addr, 4)
+            .expect("readers")
+            .into_iter()
+            .map(Arc::new)
+            .collect();
+        assert_eq!(readers.len(), 4);
+        let mut o = OTP::new();
+        for r in readers.iter() {
+            let a_reader = r.clone();
+            assert_matches!(
+                o.source(Port::Reader, move |ports| a_reader.run(ports)),
+                Ok(())
+            );
+        }
+        let b_readers = readers.clone();
+        assert_matches!(
+            o.listen(Port::Recycle, move |_ports, data| {
+                Reader::recycle_to(&b_readers, data);
+                Ok(())
+            }),
+            Ok(())
+        );
+        let rvs = Arc::new(Mutex::new(0usize));
+        let a_rvs = rvs.clone();
+        assert_matches!(
+            o.listen(Port::State, move |ports, data| match data {
+                Data::SharedMessages(msgs) => {
+                    *a_rvs.lock().unwrap() += msgs.read().unwrap().msgs.len();
+                    OTP::send(ports, Port::Recycle, Data::SharedMessages(msgs))?;
+                    Ok(())
+                }
+                _ => Ok(()),
+            }),
+            Ok(())
+        );
+        let m = [data::Message::default(); 8];
+        for i in 0..32 {
+            let cli: UdpSocket = net::socket().expect("socket");
+            cli.connect(addr).expect("client");
+            let mut num = 0;
+            while num < m.len() {
+                net::write(&cli, &m, &mut num).expect("write");
+            }
+            trace!("client {:?} wrote {:?}", i, num);
+        }
+        sleep(Duration::new(1, 0));
+        assert!(o.shutdown().is_ok());
+        assert_eq!(*rvs.lock().unwrap(), 32 * 8);
+        //every buffer went back to the pool it came from
+        for r in readers.iter() {
+            let s = r.stats();
+            assert_eq!(s.free, s.allocated, "{:?}", s);
+        }
+    }
 }
@@ -21,6 +21,15 @@ pub enum Error {
     NoSpace,
     ToLarge,
     PubKeyNotFound,
+    Timeout,
+    Corrupt,
+    FeeTooLow,
+    GenesisMismatch,
+    SupplyMismatch,
+    WrongNetwork,
+    BadSignature,
+    StaleIndex,
+    WrongVersion,
 }
 
 pub type Result<T> = core::result::Result<T, Error>;
@@ -1,14 +1,26 @@
 use result::Result;
-use std::net::UdpSocket;
+use std::net::{SocketAddr, UdpSocket};
+use data;
 use net;
 use otp::Data;
+use wallet::Wallet;
 
 pub struct Sender {
     s: UdpSocket,
+    identity: Option<Wallet>,
 }
 impl Sender {
     pub fn new(sock: UdpSocket) -> Sender {
-        Sender { s: sock }
+        Sender {
+            s: sock,
+            identity: None,
+        }
+    }
+    /// the node's key pair, the first one in `w`, `Data::SendSigned` is
+    /// signed with it
+    pub fn set_identity(&mut self, w: Wallet) {
+        assert!(!w.pubkeys.is_empty());
+        self.identity = Some(w);
     }
 
     pub fn run(&self, d: Data) -> Result<()> {
@@ -20,8 +32,22 @@ impl Sender {
                     net::send_to(&self.s, &msgs, &mut num, a)?;
                 }
             }
+            Data::SendMessages(msgs, a) => self.send_all(&msgs, a)?,
+            Data::SendSigned(mut msgs, a) => {
+                if let (Some(w), Some(m)) = (self.identity.as_ref(), msgs.last_mut()) {
+                    Wallet::sign((w.privkeys[0], w.pubkeys[0]), m);
+                }
+                self.send_all(&msgs, a)?;
+            }
             _ => (),
         }
         Ok(())
     }
+    fn send_all(&self, msgs: &[data::Message], a: SocketAddr) -> Result<()> {
+        let mut num = 0;
+        while num < msgs.len() {
+            net::send_to(&self.s, msgs, &mut num, a)?;
+        }
+        Ok(())
+    }
 }
@@ -5,36 +5,359 @@ use result::Result;
 use hasht::Key;
 use otp::{Data, Port, Ports, OTP};
 use std::net::SocketAddr;
+use std::collections::HashMap;
+use std::cmp::{max, min};
+use std::mem::replace;
+use std::thread::{available_parallelism, scope};
+use result::Error;
+use table::{Storage, Table};
+use history::{self, History};
+use poh::Poh;
+use limit::{self, Limiter};
+use std::time::Instant;
+use wallet::{to32b, Wallet};
+use std::path::PathBuf;
+
+/// smallest number of messages worth handing to a thread
+const MIN_CHUNK: usize = 64;
+
+/// the table never shrinks below this
+const MIN_SIZE: usize = 64;
+
+/// number of buckets of the old table moved after every batch while resizing
+const RESIZE_STEP: usize = 4096;
+
+/// what happens to accounts whose balance drops to 0
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reclaim {
+    Keep,
+    /// remove them at the end of the batch, and shrink the table once it is
+    /// less than 1/8 full
+    Remove,
+}
+
+/// coins created and destroyed since the table was loaded, the balances
+/// always add up to `total`
+#[derive(Default, Clone, Copy, Debug, PartialEq)]
+pub struct Supply {
+    /// balances loaded or added with `add_list`
+    pub minted: u128,
+    /// balances overwritten by `add_list`
+    pub burned: u128,
+    /// fees paid by executed messages, they go nowhere
+    pub fees: u128,
+}
+
+impl Supply {
+    pub fn total(&self) -> u128 {
+        self.minted
+            .saturating_sub(self.burned)
+            .saturating_sub(self.fees)
+    }
+}
+
+
+/// raw table pointer for the execution threads, they never touch the same slot
+#[derive(Clone, Copy)]
+struct Shared<T>(*mut T);
+unsafe impl<T> Send for Shared<T> {}
+unsafe impl<T> Sync for Shared<T> {}
+
+/// destination keys of the `Compact` messages in a batch, by message index
+type Targets = HashMap<usize, [u8; 32]>;
 
 #[repr(C)]
 pub struct State {
-    accounts: Vec<data::Account>,
+    accounts: Table,
+    /// table being drained into `accounts`, every bucket below `cursor` is
+    /// empty, if a key is in both tables the one in `accounts` is current
+    old: Table,
+    cursor: usize,
     used: usize,
+    threads: usize,
+    reclaim: Reclaim,
+    storage: Storage,
+    history: History,
+    poh: Poh,
+    min_fee: u64,
+    genesis: data::GetGenesis,
+    limit: Limiter,
+    supply: Supply,
+    audit: bool,
+    identity: Option<Wallet>,
 }
 
 impl State {
     pub fn new(size: usize) -> State {
+        let mut storage = Storage::Heap;
+        let accounts = storage.alloc(size).expect("heap table");
+        Self::with_tables(storage, accounts, Table::empty())
+    }
+    fn with_tables(storage: Storage, accounts: Table, old: Table) -> State {
+        let used = old.iter()
+            .chain(accounts.iter())
+            .filter(|a| !a.from.unused())
+            .count();
+        let minted = Self::sum(&old, &accounts);
         State {
-            accounts: vec![data::Account::default(); size],
-            used: 0,
+            accounts: accounts,
+            old: old,
+            cursor: 0,
+            used: used,
+            threads: available_parallelism().map(|n| n.get()).unwrap_or(1),
+            reclaim: Reclaim::Keep,
+            storage: storage,
+            history: History::new(history::DEFAULT_MAX),
+            poh: Poh::default(),
+            min_fee: 0,
+            genesis: data::GetGenesis::default(),
+            limit: Limiter::new(limit::DEFAULT_RATE, limit::DEFAULT_RATE),
+            supply: Supply {
+                minted: minted,
+                ..Supply::default()
+            },
+            audit: false,
+            identity: None,
         }
     }
-    pub fn from_list(v: &[data::Account]) -> Result<State> {
-        let mut s = Self::new(v.len() * 2);
+    /// keep the table in memory mapped files in `dir`, the accounts already
+    /// in `dir` are loaded, otherwise a table of `size` is created
+    pub fn open(dir: PathBuf, size: usize) -> Result<State> {
+        let (mut storage, mut tables) = Storage::open(dir)?;
+        let (accounts, old) = match tables.len() {
+            0 => (storage.alloc(size)?, Table::empty()),
+            1 => (tables.remove(0), Table::empty()),
+            //stopped in the middle of a resize
+            2 => (tables.remove(1), tables.remove(0)),
+            _ => return Err(Error::Corrupt),
+        };
+        let mut s = Self::with_tables(storage, accounts, old);
+        //a key in both tables was counted twice
+        s.finish_resize()?;
+        s.used = s.to_list().len();
+        Ok(s)
+    }
+    /// write the accounts back to disk
+    pub fn flush(&self) -> Result<()> {
+        self.old.flush()?;
+        self.accounts.flush()
+    }
+    /// number of used accounts
+    pub fn used(&self) -> usize {
+        self.used
+    }
+    /// add the accounts in `v`, existing ones are overwritten
+    pub fn add_list(&mut self, v: &[data::Account]) -> Result<()> {
         for a in v {
-            let fp = data::AccountT::find(&s.accounts, &a.from)?;
-            assert!(s.accounts[fp].from.unused());
-            s.accounts[fp].balance = a.balance;
-            s.accounts[fp].from = a.from;
+            if self.used * 4 > self.accounts.len() * 3 {
+                self.double()?;
+            }
+            self.promote(&a.from)?;
+            let fp = data::AccountT::find(&self.accounts, &a.from)?;
+            if self.accounts[fp].from.unused() {
+                self.used += 1;
+            }
+            self.supply.burned += u128::from(self.accounts[fp].balance);
+            self.supply.minted += u128::from(a.balance);
+            self.accounts[fp] = *a;
+        }
+        Ok(())
+    }
+    pub fn set_reclaim(&mut self, reclaim: Reclaim) {
+        self.reclaim = reclaim;
+    }
+    /// number of history entries kept per account, 0 disables the history
+    pub fn set_history(&mut self, max: usize) {
+        self.history.set_max(max);
+    }
+    /// messages paying less are answered with `State::FeeTooLow`
+    pub fn set_min_fee(&mut self, fee: u64) {
+        self.min_fee = fee;
+    }
+    /// check after every batch that the balances add up to the supply, a
+    /// batch that breaks it fails with `Error::SupplyMismatch`
+    pub fn set_audit(&mut self, audit: bool) {
+        self.audit = audit;
+    }
+    pub fn supply(&self) -> Supply {
+        self.supply
+    }
+    /// `Error::SupplyMismatch` unless the balances add up to the supply
+    pub fn audit(&self) -> Result<()> {
+        let sum = Self::sum(&self.old, &self.accounts);
+        if sum != self.supply.total() {
+            error!("balances add up to {:?}, expected {:?}", sum, self.supply);
+            return Err(Error::SupplyMismatch);
+        }
+        Ok(())
+    }
+    fn sum(old: &Table, accounts: &Table) -> u128 {
+        old.iter()
+            .chain(accounts.iter())
+            .filter(|a| !a.from.unused())
+            .map(|a| u128::from(a.balance))
+            .sum()
+    }
+    /// the node's key pair, the first one in `w`, `Kind::GetInfo` and
+    /// `Kind::LastHash` answer with its key
+    pub fn set_identity(&mut self, w: Wallet) {
+        assert!(!w.pubkeys.is_empty());
+        self.identity = Some(w);
+    }
+    fn identity_key(&self) -> [u8; 32] {
+        self.identity
+            .as_ref()
+            .map(|w| to32b(w.pubkeys[0]))
+            .unwrap_or([0; 32])
+    }
+    /// the latest PoH entry for the `GetLastHash` in `req`, it carries the
+    /// request's nonce so each one needs its own signature, which the
+    /// `Sender` adds with `Data::SendSigned`
+    fn last_hash(&self, req: &data::Message) -> data::Message {
+        let mut e = data::Message::default();
+        e.pld.kind = data::Kind::LastHash;
+        e.pld.network = self.genesis.network_id;
+        e.pld.lvh = self.poh.hash;
+        e.pld.lvh_count = self.poh.count;
+        e.pld.get_nonce_mut().nonce.copy_from_slice(&req.sig[..32]);
+        e.pld.from = self.identity_key();
+        e
+    }
+    /// free queries a second per address, 0 is unlimited
+    pub fn set_query_rate(&mut self, rate: u64) {
+        self.limit = Limiter::new(rate, rate);
+    }
+    /// the genesis `Kind::GetGenesis` answers with, PoH starts from its hash
+    /// and messages signed for another network are answered with
+    /// `State::WrongNetwork`
+    pub fn set_genesis(&mut self, hash: [u8; 32], network_id: u32) {
+        self.genesis = data::GetGenesis {
+            hash: hash,
+            network_id: network_id,
+        };
+        if self.poh.count == 0 {
+            self.poh = Poh::new(hash);
         }
-        s.used = v.len();
+    }
+    /// advance PoH by `num` hashes
+    pub fn tick(&mut self, num: u64) {
+        self.poh.tick(num);
+    }
+    /// latest PoH entry, every executed transfer is recorded into it
+    pub fn poh(&self) -> Poh {
+        self.poh
+    }
+    /// number of threads a wave of independent messages is spread across
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+    pub fn from_list(v: &[data::Account]) -> Result<State> {
+        let mut s = Self::new(v.len() * 2);
+        s.add_list(v)?;
         return Ok(s);
     }
+    /// every used account, in table order
+    pub fn to_list(&self) -> Vec<data::Account> {
+        self.old
+            .iter()
+            .chain(self.accounts.iter())
+            .filter(|a| !a.from.unused())
+            .cloned()
+            .collect()
+    }
+    /// grow the table to at least `size` buckets in one go, for startup
+    pub fn reserve(&mut self, size: usize) -> Result<()> {
+        self.finish_resize()?;
+        if size <= self.accounts.len() {
+            return Ok(());
+        }
+        let mut v = self.storage.alloc(size)?;
+        data::AccountT::migrate(&self.accounts, &mut v)?;
+        let old = replace(&mut self.accounts, v);
+        self.storage.release(old)
+    }
+    /// switch to a table twice the size, the accounts are moved over a few
+    /// buckets per batch by `resize_step` or when they are accessed
     fn double(&mut self) -> Result<()> {
+        self.finish_resize()?;
         let size = self.accounts.len() * 2;
-        let mut v = vec![data::Account::default(); size];
+        let v = self.storage.alloc(size)?;
+        self.old = replace(&mut self.accounts, v);
+        self.cursor = 0;
+        Ok(())
+    }
+    /// move up to `num` buckets of the old table
+    fn resize_step(&mut self, num: usize) -> Result<()> {
+        let end = min(self.cursor + num, self.old.len());
+        while self.cursor < end {
+            let key = self.old[self.cursor].from;
+            //removing refills the bucket from further down, so look again
+            if key.unused() {
+                self.cursor += 1;
+            } else {
+                self.promote(&key)?;
+            }
+        }
+        if self.cursor == self.old.len() {
+            let old = replace(&mut self.old, Table::empty());
+            self.storage.release(old)?;
+            self.cursor = 0;
+        }
+        Ok(())
+    }
+    fn finish_resize(&mut self) -> Result<()> {
+        let num = self.old.len();
+        self.resize_step(num)
+    }
+    /// move `key` from the old table if it is still there
+    fn promote(&mut self, key: &[u8; 32]) -> Result<()> {
+        if self.old.is_empty() {
+            return Ok(());
+        }
+        let op = data::AccountT::find(&self.old, key)?;
+        if self.old[op].from.unused() {
+            return Ok(());
+        }
+        //copy before removing so a crash never loses the account
+        let p = data::AccountT::find(&self.accounts, key)?;
+        if self.accounts[p].from.unused() {
+            self.accounts[p] = self.old[op];
+        }
+        data::AccountT::remove(&mut self.old, key)?;
+        Ok(())
+    }
+    /// positions of both accounts in the current table
+    fn locate(&mut self, fk: &[u8; 32], tk: &[u8; 32]) -> Result<(usize, usize)> {
+        self.promote(fk)?;
+        self.promote(tk)?;
+        Self::find_accounts(&self.accounts, fk, tk)
+    }
+    /// halve the table as long as it stays at most half full
+    pub fn shrink(&mut self) -> Result<()> {
+        self.finish_resize()?;
+        let mut size = self.accounts.len();
+        while size / 2 >= MIN_SIZE && self.used * 4 <= size {
+            size /= 2;
+        }
+        if size == self.accounts.len() {
+            return Ok(());
+        }
+        let mut v = self.storage.alloc(size)?;
         data::AccountT::migrate(&self.accounts, &mut v)?;
-        self.accounts = v;
+        let old = replace(&mut self.accounts, v);
+        self.storage.release(old)
+    }
+    /// remove `key` and its history if it is an account with nothing in it
+    fn reclaim_account(&mut self, key: &[u8; 32]) -> Result<()> {
+        self.promote(key)?;
+        let p = data::AccountT::find(&self.accounts, key)?;
+        let a = self.accounts[p];
+        if a.from.unused() || a.balance != 0 {
+            return Ok(());
+        }
+        data::AccountT::remove(&mut self.accounts, key)?;
+        self.used -= 1;
+        self.history.forget(key);
         Ok(())
     }
     fn find_accounts(
@@ -68,13 +391,12 @@ impl State {
 
     fn get_balance(
         ports: &Ports,
-        state: &mut [data::Account],
+        from: &mut data::Account,
+        to: &mut data::Account,
         m: &mut data::Message,
         addr: SocketAddr,
     ) -> Result<()> {
         assert_eq!(m.pld.kind, data::Kind::GetBalance, "{:?}", m.pld.from);
-        let pos = Self::find_accounts(state, &m.pld.from, &m.pld.get_bal().key)?;
-        let (mut from, to) = Self::load_accounts(state, pos);
         if from.from != m.pld.from {
             return Ok(());
         }
@@ -82,7 +404,7 @@ impl State {
             return Ok(());
         }
         let combined = m.pld.fee;
-        Self::charge(&mut from, m, combined);
+        Self::charge(from, m, combined);
         if m.pld.state != data::State::Withdrawn {
             return Ok(());
         }
@@ -94,70 +416,492 @@ impl State {
         Ok(())
     }
 
-    fn tx(state: &mut [data::Account], m: &mut data::Message, num_new: &mut usize) -> Result<()> {
+    fn get_index(
+        ports: &Ports,
+        from: &mut data::Account,
+        to: &mut data::Account,
+        st: usize,
+        m: &mut data::Message,
+        addr: SocketAddr,
+    ) -> Result<()> {
+        assert_eq!(m.pld.kind, data::Kind::GetIndex, "{:?}", m.pld.from);
+        if from.from != m.pld.from {
+            return Ok(());
+        }
+        if from.from.unused() {
+            return Ok(());
+        }
+        let combined = m.pld.fee;
+        Self::charge(from, m, combined);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        if to.from.unused() {
+            return Ok(());
+        }
+        m.pld.get_idx_mut().index = st as u64;
+        OTP::send(ports, Port::Sender, Data::SendMessage(m.clone(), addr))?;
+        Ok(())
+    }
+
+    /// run the transfer of a `Kind::Simulate` on copies of its accounts,
+    /// returns the `Kind::Simulated` reply
+    fn dry_run(from: &data::Account, to: &data::Account, m: &data::Message) -> Result<data::Message> {
+        let mut t = *m;
+        t.pld.kind = data::Kind::Transaction;
+        t.pld.state = data::State::Unknown;
+        let mut view = [*from, *to];
+        let pos = if from.from == to.from { (0, 0) } else { (0, 1) };
+        {
+            let (f, to) = Self::load_accounts(&mut view, pos);
+            Self::tx(f, to, &mut t, &mut 0)?;
+        }
+        let mut r = *m;
+        r.pld.kind = data::Kind::Simulated;
+        r.pld.state = t.pld.state;
+        *r.pld.get_result_mut() = data::Simulated {
+            from: view[pos.0].balance,
+            to: view[pos.1].balance,
+        };
+        Ok(r)
+    }
+    fn simulate_msg(
+        ports: &Ports,
+        from: &data::Account,
+        to: &data::Account,
+        m: &data::Message,
+        addr: SocketAddr,
+    ) -> Result<()> {
+        assert_eq!(m.pld.kind, data::Kind::Simulate, "{:?}", m.pld.from);
+        let r = Self::dry_run(from, to, m)?;
+        OTP::send(ports, Port::Sender, Data::SendMessage(r, addr))?;
+        Ok(())
+    }
+    /// the outcome of the `Kind::Simulate` message `m` against the current
+    /// balances, as its reply, nothing is written
+    pub fn simulate(&self, m: &data::Message) -> Result<data::Message> {
+        assert_eq!(m.pld.kind, data::Kind::Simulate, "{:?}", m.pld.from);
+        let from = self.lookup(&m.pld.from)?;
+        let to = self.lookup(&m.pld.get_sim().to)?;
+        Self::dry_run(&from, &to, m)
+    }
+    /// copy of the account for `key` without moving it between tables
+    fn lookup(&self, key: &[u8; 32]) -> Result<data::Account> {
+        let p = data::AccountT::find(&self.accounts, key)?;
+        if !self.accounts[p].from.unused() || self.old.is_empty() {
+            return Ok(self.accounts[p]);
+        }
+        let op = data::AccountT::find(&self.old, key)?;
+        Ok(self.old[op])
+    }
+    /// the query is only charged here, `record` answers it, only the account
+    /// itself may ask, though that keeps nothing private until signatures are
+    /// verified since anyone can put any key in `pld.from`
+    fn get_history(from: &mut data::Account, m: &mut data::Message) -> Result<()> {
+        assert_eq!(m.pld.kind, data::Kind::GetHistory, "{:?}", m.pld.from);
+        if from.from != m.pld.from || m.pld.get_hist().key != m.pld.from {
+            return Ok(());
+        }
+        if from.from.unused() {
+            return Ok(());
+        }
+        let combined = m.pld.fee;
+        Self::charge(from, m, combined);
+        Ok(())
+    }
+
+    fn tx(
+        from: &mut data::Account,
+        to: &mut data::Account,
+        m: &mut data::Message,
+        num_new: &mut usize,
+    ) -> Result<()> {
         assert_eq!(m.pld.kind, data::Kind::Transaction, "{:?}", m.pld.from);
-        let pos = Self::find_accounts(state, &m.pld.from, &m.pld.get_tx().to)?;
-        let (mut from, mut to) = Self::load_accounts(state, pos);
         if from.from != m.pld.from {
             return Ok(());
         }
         if !to.from.unused() && to.from != m.pld.get_tx().to {
             return Ok(());
         }
-        let combined = m.pld.get_tx().amount + m.pld.fee;
-        Self::charge(&mut from, m, combined);
+        let (key, amount) = (m.pld.get_tx().to, m.pld.get_tx().amount);
+        let combined = match amount.checked_add(m.pld.fee) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        Self::charge(from, m, combined);
+        if m.pld.state != data::State::Withdrawn {
+            return Ok(());
+        }
+        //`to` may be `from`, so only check once it was charged
+        if to.balance.checked_add(amount).is_none() {
+            Self::refund(from, m, combined);
+            return Ok(());
+        }
+        Self::new_account(to, num_new);
+        Self::deposit(to, &key, amount, m);
+        Ok(())
+    }
+    /// `to` must already exist, the indexes were checked by `execute_wave`
+    fn compact(from: &mut data::Account, to: &mut data::Account, m: &mut data::Message) -> Result<()> {
+        assert_eq!(m.pld.kind, data::Kind::Compact, "{:?}", m.pld.from);
+        if from.from != m.pld.from || to.from.unused() {
+            return Ok(());
+        }
+        let amount = m.pld.get_compact().amount;
+        let combined = match amount.checked_add(m.pld.fee) {
+            Some(c) => c,
+            None => return Ok(()),
+        };
+        Self::charge(from, m, combined);
         if m.pld.state != data::State::Withdrawn {
             return Ok(());
         }
-        Self::new_account(&to, num_new);
-        Self::deposit(&mut to, m);
-        assert_eq!(m.pld.state, data::State::Deposited, "{:?}", m.pld.from);
+        if to.balance.checked_add(amount).is_none() {
+            Self::refund(from, m, combined);
+            return Ok(());
+        }
+        let key = to.from;
+        Self::deposit(to, &key, amount, m);
         Ok(())
     }
+    /// the two accounts message `i` touches
+    fn keys(msgs: &[data::Message], i: usize, targets: &Targets) -> Option<([u8; 32], [u8; 32])> {
+        let m = &msgs[i];
+        match m.pld.kind {
+            data::Kind::Transaction => Some((m.pld.from, m.pld.get_tx().to)),
+            data::Kind::GetBalance => Some((m.pld.from, m.pld.get_bal().key)),
+            data::Kind::GetIndex => Some((m.pld.from, m.pld.get_idx().key)),
+            data::Kind::GetHistory => Some((m.pld.from, m.pld.get_hist().key)),
+            data::Kind::Simulate => Some((m.pld.from, m.pld.get_sim().to)),
+            data::Kind::Compact => targets.get(&i).map(|t| (m.pld.from, *t)),
+            _ => None,
+        }
+    }
+    /// destination keys of the compact messages whose checks match the
+    /// accounts at their indexes, their `pld.from` is filled in from the
+    /// table, the rest are `StaleIndex`, an index handed out before a resize
+    /// still finds its account in the old table until the resize is done
+    fn targets(&self, msgs: &mut [data::Message], batch: &[(usize, SocketAddr)]) -> Targets {
+        let mut targets = Targets::new();
+        for &(i, _) in batch {
+            if msgs[i].pld.kind != data::Kind::Compact {
+                continue;
+            }
+            let c = *msgs[i].pld.get_compact();
+            let at = |ix: u64, check: u64| {
+                let find = |t: &Table| {
+                    t.get(ix as usize)
+                        .map(|a| a.from)
+                        .filter(|k| !k.unused() && k.start() as u64 == check)
+                };
+                find(&self.accounts).or_else(|| find(&self.old))
+            };
+            match (at(c.from, c.from_check), at(c.to, c.check)) {
+                (Some(f), Some(t)) if c.from != c.to => {
+                    msgs[i].pld.from = f;
+                    targets.insert(i, t);
+                }
+                _ => msgs[i].pld.state = data::State::StaleIndex,
+            }
+        }
+        targets
+    }
+    /// run one message against its accounts, returns the number of new accounts
+    fn apply(
+        ports: &Ports,
+        from: &mut data::Account,
+        (to, st): (&mut data::Account, usize),
+        m: &mut data::Message,
+        addr: SocketAddr,
+    ) -> Result<usize> {
+        let mut num_new = 0;
+        match m.pld.kind {
+            data::Kind::Transaction => Self::tx(from, to, m, &mut num_new)?,
+            data::Kind::GetBalance => Self::get_balance(ports, from, to, m, addr)?,
+            data::Kind::GetIndex => Self::get_index(ports, from, to, st, m, addr)?,
+            data::Kind::Compact => Self::compact(from, to, m)?,
+            data::Kind::GetHistory => Self::get_history(from, m)?,
+            data::Kind::Simulate => Self::simulate_msg(ports, from, to, m, addr)?,
+            _ => (),
+        }
+        Ok(num_new)
+    }
+    /// split a batch into waves, the messages of a wave touch no common
+    /// account, and the messages of an account keep their arrival order
+    fn waves(
+        msgs: &[data::Message],
+        batch: &[(usize, SocketAddr)],
+        targets: &Targets,
+    ) -> Vec<Vec<(usize, SocketAddr)>> {
+        let mut last: HashMap<[u8; 32], usize> = HashMap::new();
+        let mut waves: Vec<Vec<(usize, SocketAddr)>> = Vec::new();
+        for &(i, a) in batch {
+            let (f, t) = match Self::keys(msgs, i, targets) {
+                Some(k) => k,
+                None => continue,
+            };
+            let wave = max(last.get(&f), last.get(&t))
+                .map(|w| w + 1)
+                .unwrap_or(0);
+            last.insert(f, wave);
+            last.insert(t, wave);
+            if waves.len() == wave {
+                waves.push(Vec::new());
+            }
+            waves[wave].push((i, a));
+        }
+        waves
+    }
     fn execute(&mut self, p: &Ports, ms: &mut data::Messages) -> Result<()> {
         ms.with_mut(
             &mut |msgs: &mut Vec<data::Message>, data: &mut Vec<(usize, SocketAddr)>| {
+                let mut batch = Vec::new();
                 let mut total = 0;
                 for &(z, a) in data.iter() {
-                    for m in msgs[total..total + z].iter_mut() {
-                        let len = self.accounts.len();
-                        if self.used * 4 > len * 3 {
-                            self.double()?;
-                        }
-                        match m.pld.kind {
-                            data::Kind::Transaction => {
-                                let mut num_new = 0;
-                                Self::tx(&mut self.accounts, m, &mut num_new)?;
-                                assert_eq!(m.pld.state, data::State::Deposited);
-                                self.used += num_new;
-                            }
-                            data::Kind::GetBalance => {
-                                Self::get_balance(p, &mut self.accounts, m, a)?;
-                            }
-                            _ => (),
+                    batch.extend((total..total + z).map(|i| (i, a)));
+                    total += z;
+                }
+                //the state is only zero when signed, a message must not
+                //arrive already `Withdrawn` and skip the withdraw, and
+                //`record` relies on it being `Deposited` only when applied
+                for &(i, _) in batch.iter() {
+                    msgs[i].pld.state = data::State::Unknown;
+                }
+                let now = Instant::now();
+                self.admit(p, msgs, &mut batch, now)?;
+                let targets = self.targets(msgs, &batch);
+                for wave in Self::waves(msgs, &batch, &targets) {
+                    self.execute_wave(p, msgs, &wave, &targets)?;
+                }
+                for &(i, _) in batch.iter() {
+                    self.supply.fees += u128::from(Self::fee_paid(&msgs[i]));
+                }
+                self.record(p, msgs, &batch, &targets)?;
+                self.resize_step(RESIZE_STEP)?;
+                if self.reclaim == Reclaim::Remove {
+                    for &(i, _) in batch.iter() {
+                        if let Some((f, t)) = Self::keys(msgs, i, &targets) {
+                            self.reclaim_account(&f)?;
+                            self.reclaim_account(&t)?;
                         }
                     }
-                    total += z;
+                    if self.used * 8 < self.accounts.len() {
+                        self.shrink()?;
+                    }
+                }
+                if self.audit {
+                    self.audit()?;
                 }
                 Ok(())
             },
         )
     }
+    /// take the messages signed for another network or paying less than the
+    /// minimum fee and the free queries over their address's rate out of the
+    /// batch, only the senders of the former are told
+    fn admit(
+        &mut self,
+        p: &Ports,
+        msgs: &mut [data::Message],
+        batch: &mut Vec<(usize, SocketAddr)>,
+        now: Instant,
+    ) -> Result<()> {
+        let mut keep = Vec::with_capacity(batch.len());
+        for &(i, a) in batch.iter() {
+            let m = &mut msgs[i];
+            if m.pld.is_free() {
+                if self.limit.allow(a.ip(), now) {
+                    keep.push((i, a));
+                }
+                continue;
+            }
+            m.pld.state = if m.pld.version != data::VERSION {
+                data::State::WrongVersion
+            } else if m.pld.network != self.genesis.network_id {
+                data::State::WrongNetwork
+            } else if m.pld.fee < self.min_fee {
+                data::State::FeeTooLow
+            } else {
+                keep.push((i, a));
+                continue;
+            };
+            if m.pld.kind != data::Kind::Invalid {
+                OTP::send(p, Port::Sender, Data::SendMessage(*m, a))?;
+            }
+        }
+        *batch = keep;
+        Ok(())
+    }
+    /// record the executed transfers into PoH and the history in arrival
+    /// order, and answer the history and free queries in between
+    fn record(
+        &mut self,
+        p: &Ports,
+        msgs: &mut [data::Message],
+        batch: &[(usize, SocketAddr)],
+        targets: &Targets,
+    ) -> Result<()> {
+        for &(i, a) in batch {
+            let m = &mut msgs[i];
+            match (m.pld.kind, m.pld.state) {
+                (data::Kind::Transaction, data::State::Deposited) => {
+                    let t = *m.pld.get_tx();
+                    let count = self.poh.record(&m.sig);
+                    self.history.record(m, t.to, t.amount, count);
+                }
+                (data::Kind::Compact, data::State::Deposited) => {
+                    let amount = m.pld.get_compact().amount;
+                    let count = self.poh.record(&m.sig);
+                    self.history.record(m, targets[&i], amount, count);
+                }
+                (data::Kind::Compact, data::State::StaleIndex) => {
+                    OTP::send(p, Port::Sender, Data::SendMessage(*m, a))?;
+                }
+                (data::Kind::GetHistory, data::State::Withdrawn) => {
+                    let q = *m.pld.get_hist();
+                    let mut v = self.history
+                        .page(&q.key, q.start as usize, q.num as usize);
+                    m.pld.get_hist_mut().num = v.len() as u32;
+                    v.insert(0, *m);
+                    OTP::send(p, Port::Sender, Data::SendMessages(v, a))?;
+                }
+                (data::Kind::GetGenesis, _) => {
+                    *m.pld.get_gen_mut() = self.genesis;
+                    OTP::send(p, Port::Sender, Data::SendMessage(*m, a))?;
+                }
+                (data::Kind::Query, _) => {
+                    //a full table doesn't have the key either
+                    let acc = self.lookup(&m.pld.get_query().key)
+                        .unwrap_or_default();
+                    if !acc.from.unused() {
+                        m.pld.state = data::State::Withdrawn;
+                    }
+                    m.pld.get_query_mut().amount = acc.balance;
+                    OTP::send(p, Port::Sender, Data::SendMessage(*m, a))?;
+                }
+                (data::Kind::GetLastHash, _) => {
+                    let e = self.last_hash(m);
+                    OTP::send(p, Port::Sender, Data::SendSigned(vec![*m, e], a))?;
+                }
+                (data::Kind::GetInfo, _) => {
+                    m.pld.from = self.identity_key();
+                    m.pld.lvh = self.poh.hash;
+                    m.pld.lvh_count = self.poh.count;
+                    *m.pld.get_info_mut() = data::Info {
+                        supply: min(self.supply.total(), u128::from(u64::max_value())) as u64,
+                        used: self.used as u64,
+                        capacity: self.accounts.len() as u64,
+                        version: data::VERSION,
+                        unused: 0,
+                    };
+                    OTP::send(p, Port::Sender, Data::SendMessage(*m, a))?;
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+    fn execute_wave(
+        &mut self,
+        p: &Ports,
+        msgs: &mut [data::Message],
+        wave: &[(usize, SocketAddr)],
+        targets: &Targets,
+    ) -> Result<()> {
+        if self.used * 4 > self.accounts.len() * 3 {
+            self.double()?;
+        }
+        //only messages between existing accounts run in parallel, nothing
+        //else writes to the table until they are done
+        let mut par = Vec::new();
+        let mut ser = Vec::new();
+        for &(i, a) in wave {
+            //compact messages are found by the keys `targets` resolved, a
+            //resize may have moved the accounts since
+            let keys = Self::keys(msgs, i, targets).unwrap();
+            let pos = self.locate(&keys.0, &keys.1)?;
+            if self.accounts[pos.0].from.unused() || self.accounts[pos.1].from.unused() {
+                ser.push((i, a));
+            } else {
+                par.push((i, a, pos));
+            }
+        }
+        let threads = min(self.threads, par.len() / MIN_CHUNK).max(1);
+        let chunk = par.len().div_ceil(threads);
+        let accounts = Shared(self.accounts.as_mut_ptr());
+        let ms = Shared(msgs.as_mut_ptr());
+        let run = move |c: &[(usize, SocketAddr, (usize, usize))]| -> Result<usize> {
+            let mut num_new = 0;
+            for &(i, a, (sf, st)) in c {
+                //the wave has no two messages with the same account
+                let (from, to, m) = unsafe {
+                    (
+                        &mut *accounts.0.add(sf),
+                        &mut *accounts.0.add(st),
+                        &mut *ms.0.add(i),
+                    )
+                };
+                num_new += Self::apply(p, from, (to, st), m, a)?;
+            }
+            Ok(num_new)
+        };
+        if threads == 1 {
+            self.used += run(&par)?;
+        } else {
+            let rvs: Vec<Result<usize>> = scope(|s| {
+                let js: Vec<_> = par.chunks(chunk).map(|c| s.spawn(move || run(c))).collect();
+                js.into_iter()
+                    .map(|j| j.join().unwrap_or_else(|p| Err(Error::JoinError(p))))
+                    .collect()
+            });
+            for rv in rvs {
+                self.used += rv?;
+            }
+        }
+        for &(i, a) in ser.iter() {
+            if self.used * 4 > self.accounts.len() * 3 {
+                self.double()?;
+            }
+            let (f, t) = Self::keys(msgs, i, targets).unwrap();
+            let pos = self.locate(&f, &t)?;
+            let (from, to) = Self::load_accounts(&mut self.accounts, pos);
+            self.used += Self::apply(p, from, (to, pos.1), &mut msgs[i], a)?;
+        }
+        Ok(())
+    }
     fn charge(acc: &mut data::Account, m: &mut data::Message, combined: u64) -> () {
         if acc.balance >= combined {
             m.pld.state = data::State::Withdrawn;
             acc.balance = acc.balance - combined;
         }
     }
+    /// undo `charge`, the deposit would overflow
+    fn refund(acc: &mut data::Account, m: &mut data::Message, combined: u64) -> () {
+        acc.balance += combined;
+        m.pld.state = data::State::Unknown;
+    }
+    /// the fee `m` paid when it was executed
+    fn fee_paid(m: &data::Message) -> u64 {
+        match (m.pld.kind, m.pld.state) {
+            (data::Kind::Transaction, data::State::Withdrawn)
+            | (data::Kind::Transaction, data::State::Deposited)
+            | (data::Kind::Compact, data::State::Withdrawn)
+            | (data::Kind::Compact, data::State::Deposited)
+            | (data::Kind::GetBalance, data::State::Withdrawn)
+            | (data::Kind::GetIndex, data::State::Withdrawn)
+            | (data::Kind::GetHistory, data::State::Withdrawn) => m.pld.fee,
+            _ => 0,
+        }
+    }
     fn new_account(to: &data::Account, num: &mut usize) -> () {
         if to.from.unused() {
             *num = *num + 1;
         }
     }
-    fn deposit(to: &mut data::Account, m: &mut data::Message) -> () {
-        to.balance = to.balance + m.pld.get_tx().amount;
+    fn deposit(to: &mut data::Account, key: &[u8; 32], amount: u64, m: &mut data::Message) -> () {
+        to.balance = to.balance + amount;
         if to.from.unused() {
-            to.from = m.pld.get_tx().to;
+            to.from = *key;
             assert!(!to.from.unused());
         }
         m.pld.state = data::State::Deposited;
@@ -166,23 +910,30 @@ impl State {
 
 #[cfg(test)]
 mod tests {
-    use state::State;
+    use state::{Reclaim, State};
+    use result::Error;
+    use wallet::{to32b, Wallet};
     use reader::Reader;
     use data;
-    use std::sync::{Arc, Mutex};
+    use std::sync::{Arc, Mutex, RwLock};
     use net;
     use std::net::UdpSocket;
     use hasht::Key;
-    use otp::OTP;
+    use otp::{Ports, OTP};
     use otp::Port;
-    use otp::Data::{SharedMessages, Signal};
+    use otp::Data::{SendMessage, SendMessages, SendSigned, SharedMessages, Signal};
+    use sender::Sender;
     use env_logger;
+    use std::collections::HashMap;
+    use rand::{Rng, SeedableRng, StdRng};
+    use std::env::temp_dir;
+    use std::fs::{read_dir, remove_dir_all};
 
     #[test]
     fn state_test() {
         let mut s: State = State::new(64);
         let mut msgs = data::Messages::new();
-        let ports = vec![];
+        let ports = Ports::default();
         s.execute(&ports, &mut msgs).expect("e");
     }
 
@@ -262,6 +1013,775 @@ mod tests {
         assert!(o.join().is_ok());
     }
 
+    #[test]
+    fn state_step_test() {
+        const NUM: usize = 4usize;
+        let f = [255u8; 32];
+        let list = [
+            data::Account {
+                from: f,
+                balance: NUM as u64 * 3u64,
+            },
+        ];
+        let state = Arc::new(Mutex::new(State::from_list(&list).expect("from list")));
+        let mut o = OTP::deterministic(0);
+        let a_state = state.clone();
+        assert_matches!(
+            o.listen(Port::State, move |p, d| a_state.lock().unwrap().run(p, d)),
+            Ok(())
+        );
+        assert_matches!(o.listen(Port::Recycle, move |_p, _d| Ok(())), Ok(()));
+        let ports = o.ports();
+        let balance = |k: &[u8; 32]| {
+            let s = state.lock().unwrap();
+            let p = data::AccountT::find(&s.accounts, k).expect("find");
+            s.accounts[p].balance
+        };
+        for i in 0..2 {
+            let mut msgs = data::Messages::new();
+            msgs.with_mut(|m, d| {
+                init_msgs(&mut m[..NUM]);
+                d[0].0 = NUM / 2;
+                Ok(())
+            }).expect("init msgs");
+            let shared = Arc::new(RwLock::new(msgs));
+            OTP::send(&ports, Port::State, SharedMessages(shared)).expect("send");
+            assert_matches!(o.step(), Ok(Some(Port::State)));
+            assert_eq!(balance(&f), (NUM as u64 / 2) * 3 * (1 - i));
+            assert_matches!(o.step(), Ok(Some(Port::Recycle)));
+        }
+        assert_matches!(o.step(), Ok(None));
+    }
+
+    fn key(i: usize) -> [u8; 32] {
+        let mut k = [7u8; 32];
+        k[7] = i as u8;
+        k[6] = (i >> 8) as u8;
+        k
+    }
+    fn execute_random(threads: usize, seed: usize) -> (State, HashMap<[u8; 32], u64>) {
+        const FUNDED: usize = 1024;
+        let list: Vec<data::Account> = (0..FUNDED)
+            .map(|i| data::Account {
+                from: key(i),
+                balance: 100,
+            })
+            .collect();
+        let mut model: HashMap<[u8; 32], u64> = list.iter().map(|a| (a.from, a.balance)).collect();
+        let mut s = State::from_list(&list).expect("from list");
+        s.set_threads(threads);
+        let mut rng = StdRng::from_seed(&[seed]);
+        let ports = Ports::default();
+        for _ in 0..4 {
+            let mut msgs = data::Messages::new();
+            msgs.with_mut(|m, d| {
+                for m in m.iter_mut() {
+                    m.pld.kind = data::Kind::Transaction;
+                    m.pld.from = key(rng.gen_range(0, FUNDED));
+                    m.pld.get_tx_mut().to = key(rng.gen_range(0, FUNDED * 2));
+                    m.pld.get_tx_mut().amount = rng.gen_range(0, 100);
+                    m.pld.fee = 1;
+                }
+                d[0].0 = m.len();
+                Ok(())
+            }).expect("init");
+            for m in msgs.msgs.iter() {
+                let combined = m.pld.get_tx().amount + m.pld.fee;
+                match model.get_mut(&m.pld.from) {
+                    Some(b) if *b >= combined => *b -= combined,
+                    _ => continue,
+                }
+                *model.entry(m.pld.get_tx().to).or_insert(0) += m.pld.get_tx().amount;
+            }
+            s.execute(&ports, &mut msgs).expect("execute");
+        }
+        (s, model)
+    }
+    #[test]
+    fn state_parallel_test() {
+        let (parallel, model) = execute_random(4, 1);
+        let (serial, _) = execute_random(1, 1);
+        let balances = |s: &State| -> HashMap<[u8; 32], u64> {
+            s.to_list().iter().map(|a| (a.from, a.balance)).collect()
+        };
+        assert_eq!(balances(&parallel), model);
+        assert_eq!(balances(&serial), model);
+        assert_eq!(parallel.used, model.len());
+    }
+
+    #[test]
+    fn state_compact_test() {
+        let list = [
+            data::Account {
+                from: key(1),
+                balance: 20,
+            },
+            data::Account {
+                from: key(2),
+                balance: 1,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        let mut o = OTP::deterministic(0);
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let a_replies = replies.clone();
+        assert_matches!(
+            o.listen(Port::Sender, move |_p, d| {
+                if let SendMessage(m, _) = d {
+                    a_replies.lock().unwrap().push(m);
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        let ports = o.ports();
+        let run = |s: &mut State, m: data::Message| -> data::Message {
+            let mut msgs = data::Messages::new();
+            msgs.msgs[0] = m;
+            msgs.data[0].0 = 1;
+            s.execute(&ports, &mut msgs).expect("execute");
+            msgs.msgs[0]
+        };
+        let index = |s: &mut State, k: [u8; 32]| -> u64 {
+            let mut m = data::Message::default();
+            m.pld.kind = data::Kind::GetIndex;
+            m.pld.from = key(1);
+            m.pld.fee = 1;
+            m.pld.get_idx_mut().key = k;
+            run(s, m);
+            assert_matches!(o.step(), Ok(Some(Port::Sender)));
+            replies.lock().unwrap().pop().unwrap().pld.get_idx().index
+        };
+        let (fi, ti) = (index(&mut s, key(1)), index(&mut s, key(2)));
+        assert_eq!(s.accounts[fi as usize].from, key(1));
+        assert_eq!(s.accounts[ti as usize].from, key(2));
+        let compact = |fi: u64, ti: u64, check: [u8; 32]| {
+            let mut tx = data::CompactTx::default();
+            tx.fee = 1;
+            tx.compact = data::Compact {
+                from: fi,
+                to: ti,
+                check: check.start() as u64,
+                amount: 3,
+                from_check: key(1).start() as u64,
+            };
+            tx.to_message()
+        };
+        let stale = |s: &mut State, m: data::Message| {
+            let m = run(s, m);
+            assert_eq!(m.pld.state, data::State::StaleIndex);
+            assert_matches!(o.step(), Ok(Some(Port::Sender)));
+            let r = replies.lock().unwrap().pop().unwrap();
+            assert_eq!(r.pld.state, data::State::StaleIndex);
+            assert_eq!(r.sig[..], m.sig[..]);
+        };
+        let m = run(&mut s, compact(fi, ti, key(2)));
+        assert_eq!(m.pld.state, data::State::Deposited);
+        assert_eq!(m.pld.from, key(1));
+        assert_eq!(s.accounts[fi as usize].balance, 20 - 2 - 4);
+        assert_eq!(s.accounts[ti as usize].balance, 4);
+        //a check names another key, or an index is out of the table
+        stale(&mut s, compact(fi, ti, key(3)));
+        stale(&mut s, compact(fi, 1 << 40, key(2)));
+        stale(&mut s, compact(ti, ti, key(2)));
+        //a resize moves the accounts, the old indexes stop working
+        s.reserve(4096).expect("reserve");
+        if s.accounts[ti as usize].from != key(2) {
+            stale(&mut s, compact(fi, ti, key(2)));
+        }
+        let (fi, ti) = (index(&mut s, key(1)), index(&mut s, key(2)));
+        let m = run(&mut s, compact(fi, ti, key(2)));
+        assert_eq!(m.pld.state, data::State::Deposited);
+        assert_eq!(s.accounts[ti as usize].balance, 7);
+        assert_eq!(s.accounts[fi as usize].balance, 20 - 2 - 4 - 2 - 4);
+        //indexes from before a resize work until it is done
+        let (fi, ti) = (index(&mut s, key(1)), index(&mut s, key(2)));
+        s.double().expect("double");
+        assert_eq!(s.old[fi as usize].from, key(1));
+        assert_eq!(s.old[ti as usize].from, key(2));
+        let m = run(&mut s, compact(fi, ti, key(2)));
+        assert_eq!(m.pld.state, data::State::Deposited);
+        assert_eq!(s.lookup(&key(2)).expect("key 2").balance, 10);
+        assert_eq!(s.lookup(&key(1)).expect("key 1").balance, 8 - 2 - 4);
+        s.finish_resize().expect("finish");
+        if s.accounts[ti as usize].from != key(2) {
+            stale(&mut s, compact(fi, ti, key(2)));
+        }
+    }
+
+    #[test]
+    fn state_history_test() {
+        let list = [
+            data::Account {
+                from: key(1),
+                balance: 20,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        let mut o = OTP::deterministic(0);
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let a_replies = replies.clone();
+        assert_matches!(
+            o.listen(Port::Sender, move |_p, d| {
+                if let SendMessages(v, _) = d {
+                    a_replies.lock().unwrap().push(v);
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        let ports = o.ports();
+        let mut msgs = data::Messages::new();
+        msgs.with_mut(|m, d| {
+            for (i, m) in m[..3].iter_mut().enumerate() {
+                m.pld.kind = data::Kind::Transaction;
+                m.pld.from = key(1);
+                m.pld.fee = 1;
+                m.pld.get_tx_mut().to = key(2);
+                m.pld.get_tx_mut().amount = i as u64 + 1;
+                m.sig[0] = i as u8;
+            }
+            //more than the account has left, not recorded
+            m[2].pld.get_tx_mut().amount = 100;
+            m[3].pld.kind = data::Kind::GetHistory;
+            m[3].pld.from = key(1);
+            m[3].pld.fee = 1;
+            m[3].pld.get_hist_mut().key = key(1);
+            m[3].pld.get_hist_mut().num = 8;
+            //someone else's history is neither charged nor answered
+            m[4] = m[3];
+            m[4].pld.get_hist_mut().key = key(2);
+            d[0].0 = 5;
+            Ok(())
+        }).expect("init");
+        s.execute(&ports, &mut msgs).expect("execute");
+        assert_eq!(s.poh().count, 2);
+        assert_matches!(o.step(), Ok(Some(Port::Sender)));
+        let v = replies.lock().unwrap().pop().unwrap();
+        assert_eq!(v.len(), 3);
+        assert_eq!(v[0].pld.get_hist().num, 2);
+        assert_eq!(v[1].pld.state, data::State::Withdrawn);
+        assert_eq!(v[1].pld.get_entry().counterparty, key(2));
+        assert_eq!(v[1].pld.get_entry().amount, 2);
+        assert_eq!(v[1].pld.lvh_count, 2);
+        assert_eq!(v[1].sig[0], 1);
+        assert_eq!(v[2].pld.get_entry().amount, 1);
+        assert_eq!(v[2].pld.lvh_count, 1);
+        assert_eq!(s.history.page(&key(2), 0, 8).len(), 2);
+        //nothing for the query of key 2
+        assert_matches!(o.step(), Ok(None));
+        //two transfers with their fees and one history query
+        assert_eq!(s.lookup(&key(1)).expect("key 1").balance, 20 - 5 - 1);
+    }
+
+    #[test]
+    fn state_simulate_test() {
+        let list = [
+            data::Account {
+                from: key(1),
+                balance: 10,
+            },
+            data::Account {
+                from: key(2),
+                balance: 1,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        let sim = |to: [u8; 32], amount: u64| {
+            let mut m = data::Message::default();
+            m.pld.kind = data::Kind::Simulate;
+            m.pld.from = key(1);
+            m.pld.fee = 1;
+            m.pld.get_sim_mut().to = to;
+            m.pld.get_sim_mut().amount = amount;
+            m
+        };
+        let r = s.simulate(&sim(key(2), 4)).expect("simulate");
+        assert_eq!(r.pld.state, data::State::Deposited);
+        assert_eq!((r.pld.get_result().from, r.pld.get_result().to), (5, 5));
+        let r = s.simulate(&sim(key(3), 4)).expect("new account");
+        assert_eq!((r.pld.get_result().from, r.pld.get_result().to), (5, 4));
+        let r = s.simulate(&sim(key(1), 4)).expect("self");
+        assert_eq!((r.pld.get_result().from, r.pld.get_result().to), (9, 9));
+        let r = s.simulate(&sim(key(2), 10)).expect("too much");
+        assert_eq!(r.pld.state, data::State::Unknown);
+        assert_eq!((r.pld.get_result().from, r.pld.get_result().to), (10, 1));
+
+        let mut o = OTP::deterministic(0);
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let a_replies = replies.clone();
+        assert_matches!(
+            o.listen(Port::Sender, move |_p, d| {
+                if let SendMessage(m, _) = d {
+                    a_replies.lock().unwrap().push(m);
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        let mut msgs = data::Messages::new();
+        msgs.msgs[0] = sim(key(3), 4);
+        msgs.data[0].0 = 1;
+        s.execute(&o.ports(), &mut msgs).expect("execute");
+        assert_matches!(o.step(), Ok(Some(Port::Sender)));
+        let r = replies.lock().unwrap().pop().unwrap();
+        assert_eq!(r.pld.kind, data::Kind::Simulated);
+        assert_eq!(r.sig[..], msgs.msgs[0].sig[..]);
+        assert_eq!((r.pld.get_result().from, r.pld.get_result().to), (5, 4));
+        assert_eq!(s.used, 2);
+        let balances: Vec<u64> = s.to_list().iter().map(|a| a.balance).collect();
+        assert_eq!(balances.iter().sum::<u64>(), 11);
+        //free, so rate limited per address like the queries
+        s.set_query_rate(1);
+        for i in 0..3 {
+            msgs.msgs[i] = sim(key(2), 4);
+            msgs.msgs[i].sig[0] = i as u8;
+        }
+        msgs.data[0].0 = 3;
+        s.execute(&o.ports(), &mut msgs).expect("execute");
+        assert_matches!(o.step(), Ok(Some(Port::Sender)));
+        assert_matches!(o.step(), Ok(None));
+        assert_eq!(replies.lock().unwrap().pop().unwrap().sig[0], 0);
+        let balances: Vec<u64> = s.to_list().iter().map(|a| a.balance).collect();
+        assert_eq!(balances.iter().sum::<u64>(), 11);
+    }
+
+    #[test]
+    fn state_record_test() {
+        let list = [
+            data::Account {
+                from: key(1),
+                balance: 1,
+            },
+        ];
+        for &state in [data::State::Deposited, data::State::Withdrawn].iter() {
+            let mut s = State::from_list(&list).expect("from list");
+            let mut msgs = data::Messages::new();
+            {
+                let m = &mut msgs.msgs[0];
+                m.pld.kind = data::Kind::Transaction;
+                m.pld.from = key(1);
+                m.pld.get_tx_mut().to = key(2);
+                m.pld.get_tx_mut().amount = 5;
+                m.pld.state = state;
+            }
+            msgs.data[0].0 = 1;
+            s.execute(&Ports::default(), &mut msgs).expect("execute");
+            //the transfer is underfunded no matter what state it came with
+            assert_eq!(msgs.msgs[0].pld.state, data::State::Unknown);
+            assert_eq!(s.poh().count, 0);
+            assert!(s.history.page(&key(2), 0, 8).is_empty());
+            let v = s.to_list();
+            assert_eq!(v.len(), 1);
+            assert_eq!(v[0].balance, 1);
+        }
+    }
+
+    #[test]
+    fn state_query_test() {
+        let list = [
+            data::Account {
+                from: key(1),
+                balance: 10,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        s.set_min_fee(1);
+        s.set_query_rate(3);
+        let mut o = OTP::deterministic(0);
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let a_replies = replies.clone();
+        assert_matches!(
+            o.listen(Port::Sender, move |_p, d| {
+                if let SendMessage(m, _) = d {
+                    a_replies.lock().unwrap().push(m);
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        let query = |k: [u8; 32], n: u8| {
+            let mut m = data::Message::default();
+            m.pld.kind = data::Kind::Query;
+            m.pld.get_query_mut().key = k;
+            m.sig[0] = n;
+            m
+        };
+        let mut msgs = data::Messages::new();
+        let m = &mut msgs.msgs[0];
+        m.pld.kind = data::Kind::Transaction;
+        m.pld.from = key(1);
+        m.pld.fee = 1;
+        m.pld.get_tx_mut().to = key(2);
+        m.pld.get_tx_mut().amount = 3;
+        msgs.msgs[1] = query(key(2), 1);
+        msgs.msgs[2] = query(key(3), 2);
+        msgs.msgs[3] = query(key(1), 3);
+        //over the rate of the first address
+        msgs.msgs[4] = query(key(1), 4);
+        msgs.msgs[5] = query(key(1), 5);
+        msgs.data[0].0 = 5;
+        msgs.data[1] = (1, "127.0.0.2:0".parse().unwrap());
+        s.execute(&o.ports(), &mut msgs).expect("execute");
+        for _ in 0..4 {
+            assert_matches!(o.step(), Ok(Some(Port::Sender)));
+        }
+        assert_matches!(o.step(), Ok(None));
+        let r = replies.lock().unwrap();
+        let got: Vec<(u8, data::State, u64)> = r.iter()
+            .map(|m| (m.sig[0], m.pld.state, m.pld.get_query().amount))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (1, data::State::Withdrawn, 3),
+                (2, data::State::Unknown, 0),
+                (3, data::State::Withdrawn, 6),
+                (5, data::State::Withdrawn, 6),
+            ]
+        );
+        //the queries did not create or charge anything
+        assert_eq!(s.used, 2);
+        let balances: Vec<u64> = s.to_list().iter().map(|a| a.balance).collect();
+        assert_eq!(balances.iter().sum::<u64>(), 9);
+    }
+
+    #[test]
+    fn state_info_test() {
+        let list = [
+            data::Account {
+                from: key(1),
+                balance: 10,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let id = to32b(w.pubkeys[0]);
+        s.set_identity(w);
+        s.set_genesis([7; 32], 1);
+        s.tick(3);
+        let mut o = OTP::deterministic(0);
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let a_replies = replies.clone();
+        assert_matches!(
+            o.listen(Port::Sender, move |_p, d| {
+                if let SendMessage(m, _) = d {
+                    a_replies.lock().unwrap().push(m);
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        let mut msgs = data::Messages::new();
+        msgs.msgs[0].pld.kind = data::Kind::GetInfo;
+        msgs.data[0].0 = 1;
+        s.execute(&o.ports(), &mut msgs).expect("execute");
+        assert_matches!(o.step(), Ok(Some(Port::Sender)));
+        let r = replies.lock().unwrap().pop().unwrap();
+        assert_eq!(r.pld.from, id);
+        assert_eq!(r.pld.lvh, s.poh().hash);
+        assert_eq!(r.pld.lvh_count, 3);
+        let i = r.pld.get_info();
+        assert_eq!((i.supply, i.used, i.capacity), (10, 1, 2));
+        assert_eq!(i.version, data::VERSION);
+    }
+
+    #[test]
+    fn state_last_hash_test() {
+        let mut s = State::new(64);
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let id = to32b(w.pubkeys[0]);
+        s.set_identity(w.clone());
+        s.set_genesis([7; 32], 1);
+        s.tick(2);
+        let mut o = OTP::deterministic(0);
+        let replies = Arc::new(Mutex::new(Vec::new()));
+        let a_replies = replies.clone();
+        assert_matches!(
+            o.listen(Port::Sender, move |_p, d| {
+                if let SendSigned(v, _) = d {
+                    a_replies.lock().unwrap().push(v);
+                }
+                Ok(())
+            }),
+            Ok(())
+        );
+        let mut msgs = data::Messages::new();
+        msgs.msgs[0] = Wallet::get_last_hash();
+        msgs.data[0].0 = 1;
+        s.execute(&o.ports(), &mut msgs).expect("execute");
+        assert_matches!(o.step(), Ok(Some(Port::Sender)));
+        let v = replies.lock().unwrap().pop().unwrap();
+        assert_eq!(v.len(), 2);
+        assert_eq!(v[0].sig[..], msgs.msgs[0].sig[..]);
+        let e = v[1];
+        assert_eq!(e.pld.kind, data::Kind::LastHash);
+        assert_eq!((e.pld.from, e.pld.network), (id, 1));
+        assert_eq!((e.pld.lvh, e.pld.lvh_count), (s.poh().hash, 2));
+        assert_eq!(e.pld.get_nonce().nonce[..], msgs.msgs[0].sig[..32]);
+        //State leaves the signing to the Sender
+        assert!(!Wallet::verify(&e));
+        let cli = net::socket().expect("socket");
+        let mut sender = Sender::new(net::socket().expect("socket"));
+        sender.set_identity(w);
+        let addr = cli.local_addr().expect("addr");
+        sender.run(SendSigned(v, addr)).expect("send");
+        let mut got = [data::Message::default(); 64];
+        let mut d = [(0, addr); 64];
+        net::read_from(&cli, &mut got, &mut d).expect("read");
+        assert_eq!(d[0].0, 2);
+        assert_eq!(got[1].pld.lvh_count, 2);
+        assert!(Wallet::verify(&got[1]));
+        //another request gets an entry for its own nonce
+        s.tick(1);
+        let req = Wallet::get_last_hash();
+        let later = s.last_hash(&req);
+        assert_eq!(later.pld.lvh_count, 3);
+        assert_eq!(later.pld.get_nonce().nonce[..], req.sig[..32]);
+    }
+
+    #[test]
+    fn state_overflow_test() {
+        let list = [
+            data::Account {
+                from: key(1),
+                balance: u64::max_value(),
+            },
+            data::Account {
+                from: key(2),
+                balance: 10,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        s.set_audit(true);
+        let mut msgs = data::Messages::new();
+        let txs = [(2, 1, 5), (1, 2, u64::max_value()), (2, 3, 5)];
+        for (m, &(f, t, amount)) in msgs.msgs.iter_mut().zip(txs.iter()) {
+            m.pld.kind = data::Kind::Transaction;
+            m.pld.from = key(f);
+            m.pld.fee = 1;
+            m.pld.get_tx_mut().to = key(t);
+            m.pld.get_tx_mut().amount = amount;
+        }
+        msgs.data[0].0 = txs.len();
+        s.execute(&Ports::default(), &mut msgs).expect("execute");
+        let states: Vec<data::State> = msgs.msgs[..3].iter().map(|m| m.pld.state).collect();
+        assert_eq!(
+            states,
+            vec![
+                data::State::Unknown,
+                data::State::Unknown,
+                data::State::Deposited,
+            ]
+        );
+        let mut list = s.to_list();
+        list.sort_by_key(|a| a.balance);
+        let balances: Vec<u64> = list.iter().map(|a| a.balance).collect();
+        assert_eq!(balances, vec![4, 5, u64::max_value()]);
+        assert_eq!(s.supply().fees, 1);
+        assert_eq!(
+            s.supply().total(),
+            u128::from(u64::max_value()) + 9
+        );
+    }
+
+    #[test]
+    fn state_audit_test() {
+        let list = [
+            data::Account {
+                from: key(1),
+                balance: 10,
+            },
+        ];
+        let mut s = State::from_list(&list).expect("from list");
+        s.set_audit(true);
+        s.add_list(&[
+            data::Account {
+                from: key(1),
+                balance: 4,
+            },
+        ]).expect("overwrite");
+        assert_eq!(s.supply().minted, 14);
+        assert_eq!(s.supply().burned, 10);
+        let mut msgs = data::Messages::new();
+        let m = &mut msgs.msgs[0];
+        m.pld.kind = data::Kind::Transaction;
+        m.pld.from = key(1);
+        m.pld.fee = 1;
+        m.pld.get_tx_mut().to = key(2);
+        m.pld.get_tx_mut().amount = 1;
+        msgs.data[0].0 = 1;
+        s.execute(&Ports::default(), &mut msgs).expect("execute");
+        assert_eq!(s.supply().fees, 1);
+        assert_eq!(s.supply().total(), 3);
+        //coins out of nowhere stop the next batch
+        let p = data::AccountT::find(&s.accounts, &key(1)).expect("find");
+        s.accounts[p].balance += 1;
+        msgs.data[0].0 = 0;
+        assert_matches!(
+            s.execute(&Ports::default(), &mut msgs),
+            Err(Error::SupplyMismatch)
+        );
+    }
+
+    #[test]
+    fn state_reclaim_test() {
+        const NUM: usize = 200;
+        let list: Vec<data::Account> = (0..NUM)
+            .map(|i| data::Account {
+                from: key(i),
+                balance: 3,
+            })
+            .collect();
+        let mut s = State::from_list(&list).expect("from list");
+        s.set_reclaim(Reclaim::Remove);
+        let ports = Ports::default();
+        let mut msgs = data::Messages::new();
+        //everyone but the last 10 accounts sends everything to account 0
+        msgs.with_mut(|m, d| {
+            for (i, m) in m[..NUM - 10].iter_mut().enumerate() {
+                m.pld.kind = data::Kind::Transaction;
+                m.pld.from = key(i + 1);
+                m.pld.get_tx_mut().to = key(0);
+                m.pld.get_tx_mut().amount = 2;
+                m.pld.fee = 1;
+            }
+            d[0].0 = NUM - 10;
+            Ok(())
+        }).expect("init");
+        s.execute(&ports, &mut msgs).expect("execute");
+        assert_eq!(s.used, 10);
+        assert_eq!(s.accounts.len(), NUM / 2);
+        let mut list = s.to_list();
+        list.sort_by_key(|a| a.balance);
+        assert!(list[..9].iter().all(|a| a.balance == 3));
+        assert_eq!(list[9].from, key(0));
+        assert_eq!(list[9].balance, 3 + 2 * (NUM as u64 - 10));
+        for i in 1..NUM - 9 {
+            let p = data::AccountT::find(&s.accounts, &key(i)).expect("find");
+            assert!(s.accounts[p].from.unused());
+            assert_eq!(s.history.len(&key(i)), 0);
+        }
+        assert_eq!(s.history.len(&key(NUM - 9)), 0);
+        assert!(s.history.len(&key(0)) > 0);
+    }
+    #[test]
+    fn state_shrink_test() {
+        let list: Vec<data::Account> = (0..40)
+            .map(|i| data::Account {
+                from: key(i),
+                balance: 1,
+            })
+            .collect();
+        let mut s = State::new(1024);
+        for a in list.iter() {
+            let p = data::AccountT::find(&s.accounts, &a.from).expect("find");
+            s.accounts[p] = *a;
+            s.used += 1;
+        }
+        s.shrink().expect("shrink");
+        assert_eq!(s.accounts.len(), 128);
+        assert_eq!(s.to_list().len(), 40);
+        for a in list.iter() {
+            let p = data::AccountT::find(&s.accounts, &a.from).expect("find");
+            assert_eq!(s.accounts[p].balance, 1);
+        }
+    }
+
+    #[test]
+    fn state_resize_test() {
+        const FUNDED: usize = 6000;
+        let list: Vec<data::Account> = (0..FUNDED)
+            .map(|i| data::Account {
+                from: key(i),
+                balance: 10,
+            })
+            .collect();
+        let mut model: HashMap<[u8; 32], u64> = list.iter().map(|a| (a.from, a.balance)).collect();
+        let mut s = State::from_list(&list).expect("from list");
+        let ports = Ports::default();
+        let mut resizing = Vec::new();
+        for b in 0..6 {
+            let mut msgs = data::Messages::new();
+            msgs.with_mut(|m, d| {
+                for (i, m) in m.iter_mut().enumerate() {
+                    let n = b * 1024 + i;
+                    m.pld.kind = data::Kind::Transaction;
+                    m.pld.from = key(n % FUNDED);
+                    m.pld.get_tx_mut().to = key(FUNDED + n);
+                    m.pld.get_tx_mut().amount = 1;
+                    m.pld.fee = 1;
+                }
+                d[0].0 = m.len();
+                Ok(())
+            }).expect("init");
+            for m in msgs.msgs.iter() {
+                *model.get_mut(&m.pld.from).unwrap() -= 2;
+                model.insert(m.pld.get_tx().to, 1);
+            }
+            s.execute(&ports, &mut msgs).expect("execute");
+            resizing.push((s.old.len(), s.cursor));
+        }
+        //the table doubles during the 3rd batch and is drained over 3 batches
+        assert_eq!(resizing[1], (0, 0));
+        assert_eq!(resizing[2], (12000, 4096));
+        assert_eq!(resizing[3], (12000, 8192));
+        assert_eq!(resizing[4], (0, 0));
+        assert_eq!(s.accounts.len(), 24000);
+        let got: HashMap<[u8; 32], u64> = s.to_list().iter().map(|a| (a.from, a.balance)).collect();
+        assert_eq!(got, model);
+        assert_eq!(s.used, model.len());
+    }
+
+    #[test]
+    fn state_open_test() {
+        let dir = temp_dir().join("loom-state-open-test");
+        let _ = remove_dir_all(&dir);
+        let list: Vec<data::Account> = (0..40)
+            .map(|i| data::Account {
+                from: key(i),
+                balance: 10,
+            })
+            .collect();
+        {
+            let mut s = State::open(dir.clone(), 64).expect("open");
+            assert_eq!(s.used, 0);
+            s.add_list(&list).expect("add list");
+            let mut msgs = data::Messages::new();
+            msgs.with_mut(|m, d| {
+                m[0].pld.kind = data::Kind::Transaction;
+                m[0].pld.from = key(0);
+                m[0].pld.get_tx_mut().to = key(100);
+                m[0].pld.get_tx_mut().amount = 5;
+                m[0].pld.fee = 1;
+                d[0].0 = 1;
+                Ok(())
+            }).expect("init");
+            s.execute(&Ports::default(), &mut msgs).expect("execute");
+            //stop in the middle of a resize with some accounts moved over
+            s.double().expect("double");
+            for i in 0..10 {
+                s.promote(&key(i)).expect("promote");
+            }
+            assert!(!s.old.is_empty());
+            s.flush().expect("flush");
+        }
+        assert_eq!(read_dir(&dir).unwrap().count(), 2);
+        let s = State::open(dir.clone(), 64).expect("reopen");
+        assert_eq!(read_dir(&dir).unwrap().count(), 1);
+        assert!(s.old.is_empty());
+        assert_eq!(s.accounts.len(), 128);
+        assert_eq!(s.used, 41);
+        let got: HashMap<[u8; 32], u64> = s.to_list().iter().map(|a| (a.from, a.balance)).collect();
+        assert_eq!(got[&key(0)], 4);
+        assert_eq!(got[&key(100)], 5);
+        assert!((1..40).all(|i| got[&key(i)] == 10));
+        drop(s);
+        remove_dir_all(&dir).expect("cleanup");
+    }
+
     #[test]
     fn state_balance_test() {
         env_logger::init();
@@ -333,6 +1853,7 @@ mod bench {
     use data;
     use state::State;
     use hasht::Key;
+    use otp::Ports;
 
     fn init_msgs(msgs: &mut [data::Message]) {
         for (i, m) in msgs.iter_mut().enumerate() {
@@ -358,7 +1879,7 @@ mod bench {
         let from = [255u8; 32];
         let fp = data::AccountT::find(&s.accounts, &from).expect("f");
         s.accounts[fp].from = from;
-        let p = vec![];
+        let p = Ports::default();
         b.iter(|| {
             s.accounts[fp].balance = NUM as u64 * 2u64;
             assert_eq!(s.accounts[fp].from, from);
@@ -367,4 +1888,44 @@ mod bench {
             assert_eq!(s.accounts[fp].balance, (NUM / 256) as u64);
         })
     }
+    /// every message moves money between two of its own accounts
+    fn parallel_bench(b: &mut Bencher, threads: usize) {
+        const NUM: usize = 1024usize;
+        let key = |i: usize| {
+            let mut k = [255u8; 32];
+            k[7] = i as u8;
+            k[6] = (i >> 8) as u8;
+            k
+        };
+        let list: Vec<data::Account> = (0..NUM * 2)
+            .map(|i| data::Account {
+                from: key(i),
+                balance: 1 << 40,
+            })
+            .collect();
+        let mut s = State::from_list(&list).expect("from list");
+        s.set_threads(threads);
+        let mut msgs = data::Messages::new();
+        msgs.with_mut(|m, d| {
+            for (i, m) in m.iter_mut().enumerate() {
+                m.pld.kind = data::Kind::Transaction;
+                m.pld.from = key(i * 2);
+                m.pld.get_tx_mut().to = key(i * 2 + 1);
+                m.pld.fee = 1;
+                m.pld.get_tx_mut().amount = 1;
+            }
+            d[0].0 = NUM;
+            Ok(())
+        }).expect("init_msgs");
+        let p = Ports::default();
+        b.iter(|| s.execute(&p, &mut msgs).expect("execute"))
+    }
+    #[bench]
+    fn serial_bench(b: &mut Bencher) {
+        parallel_bench(b, 1)
+    }
+    #[bench]
+    fn parallel4_bench(b: &mut Bencher) {
+        parallel_bench(b, 4)
+    }
 }
@@ -0,0 +1,256 @@
+//! storage for the accounts table
+//!
+//! tables live on the heap or in memory mapped files in a data directory,
+//! the files are named `accounts.GEN` and a newer generation replaces an
+//! older one, while the table is resizing both are in use
+
+use data::Account;
+use result::{Error, Result};
+use std::fs::{create_dir_all, read_dir, remove_file, OpenOptions};
+use std::mem::size_of;
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::path::PathBuf;
+use std::ptr::null_mut;
+use std::slice;
+use nix::libc::c_void;
+use nix::sys::mman::{mmap, msync, munmap, MapFlags, MsFlags, ProtFlags};
+
+const PREFIX: &str = "accounts.";
+
+pub struct Table {
+    slots: Slots,
+}
+
+enum Slots {
+    Heap(Vec<Account>),
+    Mapped {
+        ptr: *mut Account,
+        len: usize,
+        path: PathBuf,
+    },
+}
+
+//the mapping is owned by the table like a Vec owns its buffer
+unsafe impl Send for Table {}
+
+impl Table {
+    pub fn empty() -> Table {
+        Table {
+            slots: Slots::Heap(Vec::new()),
+        }
+    }
+    /// file backing the table, if any
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self.slots {
+            Slots::Heap(_) => None,
+            Slots::Mapped { ref path, .. } => Some(path),
+        }
+    }
+    fn map(path: PathBuf, len: usize) -> Result<Table> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let size = len * size_of::<Account>();
+        if file.metadata()?.len() != size as u64 {
+            file.set_len(size as u64)?;
+        }
+        if len == 0 {
+            return Ok(Table {
+                slots: Slots::Mapped {
+                    ptr: null_mut(),
+                    len: 0,
+                    path: path,
+                },
+            });
+        }
+        let prot = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        let ptr = unsafe {
+            mmap(
+                null_mut(),
+                size,
+                prot,
+                MapFlags::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )?
+        };
+        Ok(Table {
+            slots: Slots::Mapped {
+                ptr: ptr as *mut Account,
+                len: len,
+                path: path,
+            },
+        })
+    }
+    /// write the mapped slots back to the file
+    pub fn flush(&self) -> Result<()> {
+        if let Slots::Mapped { ptr, len, .. } = self.slots {
+            if len > 0 {
+                let size = len * size_of::<Account>();
+                unsafe { msync(ptr as *mut c_void, size, MsFlags::MS_SYNC)? };
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Deref for Table {
+    type Target = [Account];
+    fn deref(&self) -> &[Account] {
+        match self.slots {
+            Slots::Heap(ref v) => v,
+            Slots::Mapped { len: 0, .. } => &[],
+            Slots::Mapped { ptr, len, .. } => unsafe { slice::from_raw_parts(ptr, len) },
+        }
+    }
+}
+
+impl DerefMut for Table {
+    fn deref_mut(&mut self) -> &mut [Account] {
+        match self.slots {
+            Slots::Heap(ref mut v) => v,
+            Slots::Mapped { len: 0, .. } => &mut [],
+            Slots::Mapped { ptr, len, .. } => unsafe { slice::from_raw_parts_mut(ptr, len) },
+        }
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if let Slots::Mapped { ptr, len, .. } = self.slots {
+            if len > 0 {
+                let size = len * size_of::<Account>();
+                let _ = unsafe { munmap(ptr as *mut c_void, size) };
+            }
+        }
+    }
+}
+
+/// where new tables are allocated
+pub enum Storage {
+    Heap,
+    Dir { dir: PathBuf, gen: usize },
+}
+
+impl Storage {
+    /// tables in `dir`, oldest first, the directory is created if needed
+    pub fn open(dir: PathBuf) -> Result<(Storage, Vec<Table>)> {
+        create_dir_all(&dir)?;
+        let mut gens = Vec::new();
+        for e in read_dir(&dir)? {
+            let name = e?.file_name();
+            let name = name.to_str().unwrap_or("");
+            if !name.starts_with(PREFIX) {
+                continue;
+            }
+            if let Ok(g) = name[PREFIX.len()..].parse::<usize>() {
+                gens.push(g);
+            }
+        }
+        gens.sort();
+        let mut tables = Vec::new();
+        for g in gens.iter() {
+            let path = dir.join(format!("{}{}", PREFIX, g));
+            let size = path.metadata()?.len() as usize;
+            if size % size_of::<Account>() != 0 {
+                return Err(Error::Corrupt);
+            }
+            tables.push(Table::map(path, size / size_of::<Account>())?);
+        }
+        let gen = gens.last().map(|g| g + 1).unwrap_or(0);
+        Ok((Storage::Dir { dir: dir, gen: gen }, tables))
+    }
+    /// a new table with `len` unused slots
+    pub fn alloc(&mut self, len: usize) -> Result<Table> {
+        match *self {
+            Storage::Heap => Ok(Table {
+                slots: Slots::Heap(vec![Account::default(); len]),
+            }),
+            Storage::Dir {
+                ref dir,
+                ref mut gen,
+            } => {
+                let path = dir.join(format!("{}{}", PREFIX, gen));
+                *gen += 1;
+                //a file left over from a crash would not be empty
+                let _ = remove_file(&path);
+                Table::map(path, len)
+            }
+        }
+    }
+    /// drop a table that is no longer used and delete its file
+    pub fn release(&mut self, t: Table) -> Result<()> {
+        let path = t.path().cloned();
+        drop(t);
+        if let Some(p) = path {
+            remove_file(p)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use table::{Storage, Table};
+    use std::env::temp_dir;
+    use std::fs::{remove_dir_all, File};
+
+    #[test]
+    fn heap_test() {
+        let mut s = Storage::Heap;
+        let mut t = s.alloc(4).expect("alloc");
+        assert_eq!(t.len(), 4);
+        t[1].balance = 3;
+        assert!(t.path().is_none());
+        assert!(t.flush().is_ok());
+        assert!(s.release(t).is_ok());
+        assert!(Table::empty().is_empty());
+    }
+    #[test]
+    fn dir_test() {
+        let dir = temp_dir().join("loom-table-dir-test");
+        let _ = remove_dir_all(&dir);
+        {
+            let (mut s, ts) = Storage::open(dir.clone()).expect("open");
+            assert!(ts.is_empty());
+            let mut a = s.alloc(8).expect("alloc");
+            let mut b = s.alloc(16).expect("alloc");
+            assert!(a.iter().all(|x| x.balance == 0));
+            a[7].balance = 7;
+            b[15].from[0] = 1;
+            b[15].balance = 15;
+            assert!(b.flush().is_ok());
+        }
+        let (mut s, mut ts) = Storage::open(dir.clone()).expect("reopen");
+        assert_eq!(ts.len(), 2);
+        assert_eq!(ts[0].len(), 8);
+        assert_eq!(ts[0][7].balance, 7);
+        assert_eq!(ts[1][15].balance, 15);
+        assert_eq!(ts[1][15].from[0], 1);
+        let path = ts[0].path().cloned().unwrap();
+        assert!(s.release(ts.remove(0)).is_ok());
+        assert!(!path.exists());
+        let c = s.alloc(2).expect("alloc");
+        assert!(c.path().unwrap().ends_with("accounts.2"));
+        drop(ts);
+        drop(c);
+        remove_dir_all(&dir).expect("cleanup");
+    }
+    #[test]
+    fn dir_corrupt_test() {
+        let dir = temp_dir().join("loom-table-corrupt-test");
+        let _ = remove_dir_all(&dir);
+        {
+            let (_, _) = Storage::open(dir.clone()).expect("open");
+        }
+        File::create(dir.join("accounts.0"))
+            .and_then(|f| f.set_len(3))
+            .expect("create");
+        assert!(Storage::open(dir.clone()).is_err());
+        remove_dir_all(&dir).expect("cleanup");
+    }
+}
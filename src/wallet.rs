@@ -11,6 +11,7 @@ use rand::Rng;
 use rand::os::OsRng;
 
 use data;
+use hasht::Key;
 use result::Result;
 use result::Error;
 use serde_json;
@@ -103,7 +104,9 @@ impl Wallet {
     }
     pub fn new_keypair() -> Keypair {
         let mut rnd: OsRng = OsRng::new().unwrap();
-        let mut seed = [0u8; 64];
+        //`signature` only uses 32 bytes of seed, a longer one gives a
+        //public key that doesn't match the signatures
+        let mut seed = [0u8; 32];
         rnd.fill_bytes(&mut seed);
         let (a, b) = ed25519::keypair(&seed);
         assert!(cfg!(target_endian = "little"));
@@ -119,6 +122,20 @@ impl Wallet {
         let pk = to64b(kp.0);
         msg.sig = ed25519::signature(buf, &pk);
     }
+    /// true if `msg` was signed by `msg.pld.from`
+    pub fn verify(msg: &data::Message) -> bool {
+        let sz = size_of::<data::Payload>();
+        let p = &msg.pld as *const data::Payload;
+        assert!(cfg!(target_endian = "little"));
+        let buf = unsafe { from_raw_parts(p as *const u8, sz) };
+        ed25519::verify(buf, &msg.pld.from, &msg.sig)
+    }
+    /// point `msg` at the PoH entry the sender last saw and sign it again
+    pub fn set_lvh(&self, key: usize, msg: &mut data::Message, hash: [u8; 32], count: u64) {
+        msg.pld.lvh = hash;
+        msg.pld.lvh_count = count;
+        Self::sign((self.privkeys[key], self.pubkeys[key]), msg);
+    }
     pub fn find(&self, from: [u8; 32]) -> Result<usize> {
         let fk = from32b(from);
         for (i, k) in self.pubkeys.iter().enumerate() {
@@ -128,7 +145,8 @@ impl Wallet {
         }
         Err(Error::PubKeyNotFound)
     }
-    pub fn tx(&self, key: usize, to: [u8; 32], amnt: u64, fee: u64) -> data::Message {
+    /// `network` is the `Genesis::network_id` the transfer is only valid on
+    pub fn tx(&self, key: usize, to: [u8; 32], amnt: u64, fee: u64, network: u32) -> data::Message {
         let data = data::MessageData {
             tx: data::Transaction {
                 to: to,
@@ -139,12 +157,46 @@ impl Wallet {
         let mut msg = data::Message::default();
         msg.pld.from = to32b(k);
         msg.pld.fee = fee;
+        msg.pld.network = network;
         msg.pld.data = data;
         msg.pld.kind = data::Kind::Transaction;
         Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
         msg
     }
-    pub fn check_balance(&self, key: usize, acc: [u8; 32], fee: u64) -> data::Message {
+    /// a `tx` that the node only tries, see `Kind::Simulate`
+    pub fn simulate(
+        &self,
+        key: usize,
+        to: [u8; 32],
+        amnt: u64,
+        fee: u64,
+        network: u32,
+    ) -> data::Message {
+        let mut msg = self.tx(key, to, amnt, fee, network);
+        msg.pld.kind = data::Kind::Simulate;
+        Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
+        msg
+    }
+    /// a free `Kind::Query` for the balance of `acc`, it isn't signed,
+    /// `sig` is random so the reply can be matched
+    pub fn query(acc: [u8; 32]) -> data::Message {
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::Query;
+        msg.pld.get_query_mut().key = acc;
+        let mut rnd: OsRng = OsRng::new().unwrap();
+        rnd.fill_bytes(&mut msg.sig);
+        msg
+    }
+    /// a free `Kind::GetLastHash`, `sig` is random so the reply can be
+    /// matched
+    pub fn get_last_hash() -> data::Message {
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::GetLastHash;
+        let mut rnd: OsRng = OsRng::new().unwrap();
+        rnd.fill_bytes(&mut msg.sig);
+        msg
+    }
+    pub fn check_balance(&self, key: usize, acc: [u8; 32], fee: u64, network: u32) -> data::Message {
         let data = data::MessageData {
             bal: data::GetBalance {
                 key: acc,
@@ -156,6 +208,95 @@ impl Wallet {
         msg.pld.kind = data::Kind::GetBalance;
         msg.pld.from = to32b(k);
         msg.pld.fee = fee;
+        msg.pld.network = network;
+        msg.pld.data = data;
+        Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
+        msg
+    }
+    /// transfer between existing accounts at table indexes `from` and `to`,
+    /// `to_key` is only used for the check
+    pub fn compact_tx(
+        &self,
+        key: usize,
+        (from, to): (u64, u64),
+        to_key: [u8; 32],
+        amnt: u64,
+        fee: u64,
+        network: u32,
+    ) -> data::CompactTx {
+        let mut tx = data::CompactTx::default();
+        tx.compact = data::Compact {
+            from: from,
+            to: to,
+            check: to_key.start() as u64,
+            amount: amnt,
+            from_check: to32b(self.pubkeys[key]).start() as u64,
+        };
+        tx.fee = fee;
+        tx.network = network;
+        Self::sign_compact((self.privkeys[key], self.pubkeys[key]), &mut tx);
+        tx
+    }
+    /// the bytes of `tx` that are signed
+    fn compact_bytes(tx: &data::CompactTx) -> &[u8] {
+        let sz = size_of::<data::CompactTx>() - tx.sig.len();
+        let p = tx as *const data::CompactTx;
+        assert!(cfg!(target_endian = "little"));
+        unsafe { from_raw_parts(p as *const u8, sz) }
+    }
+    pub fn sign_compact(kp: Keypair, tx: &mut data::CompactTx) {
+        let pk = to64b(kp.0);
+        tx.sig = ed25519::signature(Self::compact_bytes(tx), &pk);
+    }
+    /// true if `tx` was signed by `from`, the key at its source index
+    pub fn verify_compact(tx: &data::CompactTx, from: &[u8; 32]) -> bool {
+        ed25519::verify(Self::compact_bytes(tx), from, &tx.sig)
+    }
+    /// `set_lvh` for a compact transfer, only the count goes on the wire
+    pub fn set_compact_lvh(&self, key: usize, tx: &mut data::CompactTx, count: u64) {
+        tx.lvh_count = count;
+        Self::sign_compact((self.privkeys[key], self.pubkeys[key]), tx);
+    }
+    /// ask for up to `num` history entries of `acc`, skipping the `start` newest
+    pub fn get_history(
+        &self,
+        key: usize,
+        acc: [u8; 32],
+        start: u32,
+        num: u32,
+        fee: u64,
+        network: u32,
+    ) -> data::Message {
+        let data = data::MessageData {
+            hist: data::GetHistory {
+                key: acc,
+                start: start,
+                num: num,
+            },
+        };
+        let k = self.pubkeys[key];
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::GetHistory;
+        msg.pld.from = to32b(k);
+        msg.pld.fee = fee;
+        msg.pld.network = network;
+        msg.pld.data = data;
+        Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
+        msg
+    }
+    pub fn get_index(&self, key: usize, acc: [u8; 32], fee: u64, network: u32) -> data::Message {
+        let data = data::MessageData {
+            idx: data::GetIndex {
+                key: acc,
+                index: 0,
+            },
+        };
+        let k = self.pubkeys[key];
+        let mut msg = data::Message::default();
+        msg.pld.kind = data::Kind::GetIndex;
+        msg.pld.from = to32b(k);
+        msg.pld.fee = fee;
+        msg.pld.network = network;
         msg.pld.data = data;
         Self::sign((self.privkeys[key], self.pubkeys[key]), &mut msg);
         msg
@@ -184,6 +325,34 @@ mod test {
         assert_eq!(nw, ow);
     }
     #[test]
+    fn test_verify() {
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let mut msg = w.tx(0, [1; 32], 10, 1, 0);
+        assert!(Wallet::verify(&msg));
+        w.set_lvh(0, &mut msg, [2; 32], 5);
+        assert_eq!(msg.pld.lvh_count, 5);
+        assert!(Wallet::verify(&msg));
+        msg.pld.fee = 2;
+        assert!(!Wallet::verify(&msg));
+    }
+    #[test]
+    fn test_verify_compact() {
+        let mut w = Wallet::new();
+        w.add_keypair(Wallet::new_keypair());
+        let from = to32b(w.pubkeys[0]);
+        let mut tx = w.compact_tx(0, (1, 2), [1; 32], 10, 1, 0);
+        assert!(Wallet::verify_compact(&tx, &from));
+        w.set_compact_lvh(0, &mut tx, 5);
+        assert!(Wallet::verify_compact(&tx, &from));
+        assert!(!Wallet::verify_compact(&tx, &[1; 32]));
+        let m = tx.to_message();
+        assert_eq!(m.pld.lvh_count, 5);
+        assert_eq!(m.pld.get_compact().to, 2);
+        tx.compact.amount = 11;
+        assert!(!Wallet::verify_compact(&tx, &from));
+    }
+    #[test]
     fn test_find() {
         let mut w = Wallet::new();
         let kp1 = Wallet::new_keypair();